@@ -0,0 +1,36 @@
+CREATE TABLE IF NOT EXISTS games (
+    id TEXT PRIMARY KEY,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    connect INT NOT NULL,
+    strict BOOLEAN NOT NULL DEFAULT FALSE,
+    cookie_token TEXT,
+    milk_token TEXT,
+    rng_seed BIGINT NOT NULL,
+    -- random boards drawn since the RNG was seeded, to restore its position
+    random_boards INT NOT NULL DEFAULT 0,
+    -- bumped on every reset, so each played-out board gets its own result
+    round INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS moves (
+    game_id TEXT NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    n INT NOT NULL,
+    team TEXT NOT NULL,
+    board_column INT NOT NULL,
+    board_row INT NOT NULL,
+    played_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (game_id, n)
+);
+
+-- kept after their game is gone, for the totals in /12/stats
+CREATE TABLE IF NOT EXISTS game_results (
+    game_id TEXT NOT NULL,
+    round INT NOT NULL,
+    -- 'cookie', 'milk' or 'draw'
+    winner TEXT NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (game_id, round)
+);
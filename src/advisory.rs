@@ -0,0 +1,317 @@
+use std::{fs, path::Path};
+
+use semver::{Op, Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+/// Security advisories, read from a checkout of the RustSec advisory-db:
+/// `crates/<name>/<id>.md` files, each opening with a TOML block.
+pub struct Database {
+    advisories: Vec<Advisory>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Advisory {
+    advisory: Metadata,
+    #[serde(default)]
+    versions: Versions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    id: String,
+    package: String,
+    /// Given by the heading under the TOML block in `.md` advisories.
+    #[serde(default)]
+    title: String,
+    date: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Set for notices like `unmaintained` or `unsound`, which aren't
+    /// vulnerabilities.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    informational: Option<String>,
+    #[serde(default, skip_serializing)]
+    withdrawn: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Versions {
+    #[serde(default)]
+    patched: Vec<VersionReq>,
+    #[serde(default)]
+    unaffected: Vec<VersionReq>,
+}
+
+/// Advisories for one package at the version that was checked.
+#[derive(Serialize)]
+pub struct Match<'a> {
+    pub name: String,
+    pub version: Version,
+    /// The requirement the version was derived from, for manifests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub req: Option<String>,
+    pub advisories: Vec<&'a Advisory>,
+}
+
+/// Advisories found for a manifest or lockfile, by how sure it is that
+/// they apply.
+#[derive(Default, Serialize)]
+pub struct Report<'a> {
+    pub checked: usize,
+    /// Affecting every version the lockfile pins or the requirement allows.
+    pub vulnerable: Vec<Match<'a>>,
+    /// Affecting the lowest version a requirement allows, though it allows
+    /// a fixed version too, which a fresh resolve would pick.
+    pub may_be_affected: Vec<Match<'a>>,
+    /// Notices about unmaintained or unsound crates.
+    pub informational: Vec<Match<'a>>,
+}
+
+impl<'a> Report<'a> {
+    /// Check a version pinned by a lockfile.
+    pub fn check_version(&mut self, db: &'a Database, name: String, version: Version) {
+        self.checked += 1;
+        let (informational, vulnerable) = db
+            .check(&name, &version)
+            .into_iter()
+            .partition(|advisory| advisory.advisory.informational.is_some());
+        let found = Match {
+            name,
+            version,
+            req: None,
+            advisories: Vec::new(),
+        };
+        push(&mut self.vulnerable, &found, vulnerable);
+        push(&mut self.informational, &found, informational);
+    }
+
+    /// Check a manifest requirement at the lowest version it allows, the one
+    /// a lockfile may still pin it to.
+    pub fn check_req(&mut self, db: &'a Database, name: String, req: &VersionReq) {
+        self.checked += 1;
+        let version = min_version(req);
+        let (informational, rest): (Vec<_>, Vec<_>) = db
+            .check(&name, &version)
+            .into_iter()
+            .partition(|advisory| advisory.advisory.informational.is_some());
+        let (may_be_affected, vulnerable) = rest
+            .into_iter()
+            .partition(|advisory| advisory.fixed_within(req));
+        let found = Match {
+            name,
+            version,
+            req: Some(req.to_string()),
+            advisories: Vec::new(),
+        };
+        push(&mut self.vulnerable, &found, vulnerable);
+        push(&mut self.may_be_affected, &found, may_be_affected);
+        push(&mut self.informational, &found, informational);
+    }
+}
+
+fn push<'a>(matches: &mut Vec<Match<'a>>, found: &Match<'a>, advisories: Vec<&'a Advisory>) {
+    if !advisories.is_empty() {
+        matches.push(Match {
+            name: found.name.clone(),
+            version: found.version.clone(),
+            req: found.req.clone(),
+            advisories,
+        });
+    }
+}
+
+impl Database {
+    /// Advisories that can't be read are skipped, and a missing directory
+    /// gives an empty database, so a bad checkout doesn't stop the server.
+    pub fn load(dir: impl AsRef<Path>) -> Self {
+        let mut advisories = Vec::new();
+        let crates = dir.as_ref().join("crates");
+        let entries = match fs::read_dir(&crates) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("No advisories in {}: {e}", crates.display());
+                return Self { advisories };
+            }
+        };
+        for entry in entries.flatten() {
+            let Ok(files) = fs::read_dir(entry.path()) else {
+                continue;
+            };
+            for file in files.flatten() {
+                let path = file.path();
+                match Advisory::read(&path) {
+                    Ok(Some(advisory)) => advisories.push(advisory),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Skipping advisory {}: {e}", path.display()),
+                }
+            }
+        }
+        eprintln!("Loaded {} advisories", advisories.len());
+        Self { advisories }
+    }
+
+    pub fn check(&self, name: &str, version: &Version) -> Vec<&Advisory> {
+        self.advisories
+            .iter()
+            .filter(|advisory| advisory.advisory.package == name && advisory.affects(version))
+            .collect()
+    }
+}
+
+impl Advisory {
+    /// Withdrawn advisories and files that aren't advisories give `None`.
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        let (front_matter, title) = match path.extension().and_then(|e| e.to_str()) {
+            Some("md") => {
+                let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+                let (front_matter, body) = text
+                    .trim_start()
+                    .strip_prefix("```toml")
+                    .and_then(|rest| rest.split_once("```"))
+                    .ok_or("no TOML block")?;
+                let title = body
+                    .lines()
+                    .find_map(|line| line.strip_prefix("# "))
+                    .map(|title| title.trim().to_string());
+                (front_matter.to_string(), title)
+            }
+            // the format before Markdown advisories
+            Some("toml") => (fs::read_to_string(path).map_err(|e| e.to_string())?, None),
+            _ => return Ok(None),
+        };
+        let mut advisory: Self = toml::from_str(&front_matter).map_err(|e| e.to_string())?;
+        if let Some(title) = title {
+            advisory.advisory.title = title;
+        }
+        Ok(advisory.advisory.withdrawn.is_none().then_some(advisory))
+    }
+
+    /// Versions are affected unless they're patched or never were affected.
+    fn affects(&self, version: &Version) -> bool {
+        let Versions {
+            patched,
+            unaffected,
+        } = &self.versions;
+        !patched
+            .iter()
+            .chain(unaffected)
+            .any(|req| req.matches(version))
+    }
+
+    /// Whether `req` allows a patched or unaffected version. Requirements are
+    /// ranges, and two ranges overlap exactly when one contains the lowest
+    /// version of the other.
+    fn fixed_within(&self, req: &VersionReq) -> bool {
+        let lowest = min_version(req);
+        self.versions
+            .patched
+            .iter()
+            .chain(&self.versions.unaffected)
+            .any(|safe| safe.matches(&lowest) || req.matches(&min_version(safe)))
+    }
+}
+
+/// The lowest version `req` allows. Manifests only give requirements, and
+/// this is the version a lockfile may still pin them to.
+pub fn min_version(req: &VersionReq) -> Version {
+    req.comparators
+        .iter()
+        .filter_map(|c| {
+            let minor = c.minor.unwrap_or(0);
+            let patch = c.patch.unwrap_or(0);
+            match c.op {
+                Op::Exact | Op::GreaterEq | Op::Tilde | Op::Caret | Op::Wildcard => {
+                    let mut version = Version::new(c.major, minor, patch);
+                    version.pre = c.pre.clone();
+                    Some(version)
+                }
+                Op::Greater => Some(match (c.minor, c.patch) {
+                    (None, _) => Version::new(c.major + 1, 0, 0),
+                    (Some(_), None) => Version::new(c.major, minor + 1, 0),
+                    (Some(_), Some(_)) => Version::new(c.major, minor, patch + 1),
+                }),
+                _ => None,
+            }
+        })
+        .max()
+        .unwrap_or(Version::new(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(req: &str) -> VersionReq {
+        VersionReq::parse(req).unwrap()
+    }
+
+    fn advisory(patched: &[&str], unaffected: &[&str]) -> Advisory {
+        Advisory {
+            advisory: Metadata {
+                id: "RUSTSEC-0000-0000".to_string(),
+                package: "example".to_string(),
+                title: String::new(),
+                date: "2024-12-01".to_string(),
+                url: None,
+                aliases: Vec::new(),
+                informational: None,
+                withdrawn: None,
+            },
+            versions: Versions {
+                patched: patched.iter().map(|r| req(r)).collect(),
+                unaffected: unaffected.iter().map(|r| req(r)).collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn min_version_of_requirements() {
+        for (requirement, lowest) in [
+            ("^1.2", "1.2.0"),
+            ("~1.2.3", "1.2.3"),
+            ("1.2.*", "1.2.0"),
+            ("*", "0.0.0"),
+            (">1", "2.0.0"),
+            (">1.2", "1.3.0"),
+            (">1.2.3", "1.2.4"),
+            (">=1.0, <2", "1.0.0"),
+            ("<1.5", "0.0.0"),
+            ("=1.2.3-alpha.1", "1.2.3-alpha.1"),
+        ] {
+            let lowest = Version::parse(lowest).unwrap();
+            assert_eq!(min_version(&req(requirement)), lowest, "{requirement}");
+        }
+    }
+
+    #[test]
+    fn fixed_within_requirements() {
+        for (patched, unaffected, requirement, fixed) in [
+            // caret and tilde reach a patch release in their range
+            (&[">=1.2.5"][..], &[][..], "^1.2", true),
+            (&[">=2.0.0"], &[], "^1.2", false),
+            (&[">=1.2.5"], &[], "~1.2.3", true),
+            (&[">=1.2.5"], &[], "~1.1", false),
+            // `>` with a partial version skips the whole of that version
+            (&[">=1.3.0"], &[], ">1.2", true),
+            (&["^1.2.5"], &[], ">1", false),
+            (&[">=1.2.5"], &[], "*", true),
+            // patched only below the requirement
+            (&["^0.9.3"], &[], "^1.0", false),
+            (&[">=0.9.3"], &[], "^1.0", true),
+            (&["^1.4.1", "^0.9.3"], &[], "=1.0.2", false),
+            // never affected before 1.0
+            (&[], &["<1.0.0"], "^0.8", true),
+            (&[], &["<1.0.0"], "^1.2", false),
+            (&[], &[], "*", false),
+        ] {
+            let advisory = advisory(patched, unaffected);
+            assert_eq!(
+                advisory.fixed_within(&req(requirement)),
+                fixed,
+                "{requirement} with patched {patched:?}, unaffected {unaffected:?}"
+            );
+        }
+    }
+}
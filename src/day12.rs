@@ -1,85 +1,866 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{Display, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    async_trait,
+    extract::{FromRequestParts, Path, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        request::Parts,
+        HeaderMap, HeaderName, StatusCode,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
 };
-use rand::{rngs::StdRng, Rng, SeedableRng};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::extract::{Expected, Format, Query};
+
+mod ai;
+mod store;
 
 const RNG_SEED: u64 = 2024;
+/// Largest width or height a board can be created with.
+const MAX_BOARD_SIZE: usize = 16;
+/// Updates a lagging watcher can fall behind by before it starts missing some.
+const UPDATES_CAPACITY: usize = 16;
+/// Game used by the unscoped `/12/...` routes; never evicted.
+const DEFAULT_GAME: &str = "default";
 
 struct Data {
     board: Board,
     rng: StdRng,
+    last_active: Instant,
+    /// In strict games teams must alternate, and moves need the player token
+    /// handed out when joining.
+    strict: bool,
+    tokens: HashMap<Tile, String>,
+    /// Boards pushed to `/12/watch` subscribers whenever the game changes.
+    updates: broadcast::Sender<BoardUpdate>,
+    rng_seed: u64,
+    /// Random boards drawn from `rng` since it was seeded, so its position can
+    /// be restored.
+    random_boards: u32,
+    /// Number of times the game has been reset.
+    round: u32,
+    /// Where snapshots of the game go to be stored, once it's been saved.
+    writer: Option<mpsc::UnboundedSender<store::Snapshot>>,
+}
+
+type Denied = (StatusCode, &'static str);
+
+impl Data {
+    fn new(board: Board, strict: bool) -> Self {
+        Self {
+            board,
+            rng: StdRng::seed_from_u64(RNG_SEED),
+            last_active: Instant::now(),
+            strict,
+            tokens: HashMap::new(),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+            rng_seed: RNG_SEED,
+            random_boards: 0,
+            round: 0,
+            writer: None,
+        }
+    }
+
+    fn reseed(&mut self) {
+        self.rng = StdRng::seed_from_u64(self.rng_seed);
+        self.random_boards = 0;
+    }
+
+    /// Draw the next full random board with the game's dimensions.
+    fn next_random_board(&mut self) -> Board {
+        self.random_boards += 1;
+        let Board {
+            width,
+            height,
+            connect,
+            ..
+        } = self.board;
+        Board::new_random(&mut self.rng, width, height, connect, 1.0)
+    }
+
+    fn notify(&self, board: &Board) {
+        // an error only means nobody is watching
+        let _ = self.updates.send(BoardUpdate::from(board));
+    }
+
+    /// Hand out the player token for `team`, which can only be done once.
+    fn join(&mut self, team: Tile) -> Result<String, Denied> {
+        if !self.strict {
+            return Err((StatusCode::BAD_REQUEST, "Game is not in strict mode\n"));
+        }
+        if self.tokens.contains_key(&team) {
+            return Err((StatusCode::CONFLICT, "Team already joined\n"));
+        }
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        self.tokens.insert(team, token.clone());
+        Ok(token)
+    }
+
+    /// In strict games, check that `token` belongs to `team`'s player.
+    fn check_player(&self, team: Tile, token: &PlayerToken) -> Result<(), Denied> {
+        if !self.strict {
+            return Ok(());
+        }
+        match (self.tokens.get(&team), &token.0) {
+            (Some(expected), Some(token)) if expected == token => Ok(()),
+            _ => Err((StatusCode::FORBIDDEN, "Missing or invalid player token\n")),
+        }
+    }
+
+    /// In strict games, check that `team`'s player is the one to move.
+    fn check_turn(&self, team: Tile, token: &PlayerToken) -> Result<(), Denied> {
+        self.check_player(team, token)?;
+        if self.strict && self.board.winner.is_none() && self.board.turn() != Some(team) {
+            return Err((StatusCode::CONFLICT, "Not your turn\n"));
+        }
+        Ok(())
+    }
+}
+
+struct Games {
+    games: HashMap<String, Data>,
+    idle_timeout: Duration,
+    db: PgPool,
+}
+
+impl Games {
+    fn is_expired(&self, id: &str, data: &Data) -> bool {
+        id != DEFAULT_GAME && data.last_active.elapsed() >= self.idle_timeout
+    }
+
+    fn get(&self, id: &str) -> Result<&Data, StatusCode> {
+        match self.games.get(id) {
+            Some(data) if !self.is_expired(id, data) => Ok(data),
+            _ => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// Look up a game for modification, marking it as active.
+    fn get_mut(&mut self, id: &str) -> Result<&mut Data, StatusCode> {
+        self.evict_idle();
+        let data = self.games.get_mut(id).ok_or(StatusCode::NOT_FOUND)?;
+        data.last_active = Instant::now();
+        Ok(data)
+    }
+
+    fn create(&mut self, board: Board, strict: bool) -> String {
+        self.evict_idle();
+        let id = Uuid::new_v4().to_string();
+        self.games.insert(id.clone(), Data::new(board, strict));
+        id
+    }
+
+    /// Queue a game to be written to the database, once the lock on it is
+    /// released. Failures are only logged, the game in memory stays playable.
+    fn save(&mut self, id: &str) {
+        let Some(data) = self.games.get_mut(id) else {
+            return;
+        };
+        let snapshot = store::Snapshot::of(data);
+        let writer = data
+            .writer
+            .get_or_insert_with(|| store::writer(self.db.clone(), id.to_string()));
+        // the writer only stops once the game is dropped
+        let _ = writer.send(snapshot);
+    }
+
+    fn evict_idle(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.games
+            .retain(|id, data| id == DEFAULT_GAME || data.last_active.elapsed() < idle_timeout);
+    }
+}
+
+type SharedGames = Arc<RwLock<Games>>;
+
+/// Id of the game a request operates on, taken from the `:id` path segment.
+/// Routes without one operate on the default game.
+struct GameId(String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for GameId
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let id = match Path::<HashMap<String, String>>::from_request_parts(parts, state).await {
+            Ok(Path(mut params)) => params.remove("id"),
+            Err(_) => None,
+        };
+        Ok(Self(id.unwrap_or_else(|| DEFAULT_GAME.to_string())))
+    }
+}
+
+/// Player token from an `Authorization: Bearer <token>` header.
+struct PlayerToken(Option<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for PlayerToken
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        Ok(Self(token))
+    }
+}
+
+impl Format {
+    fn render(self, board: &Board) -> Response {
+        match self {
+            Self::Text => board.to_string().into_response(),
+            Self::Json => Json(BoardView::from(board)).into_response(),
+        }
+    }
 }
 
-pub fn router() -> Router {
-    let board = Board::default();
-    let rng = StdRng::seed_from_u64(RNG_SEED);
+/// Idle games are evicted once they haven't changed for `idle_timeout`.
+/// Games stored in `pool` by a previous run are picked up again.
+pub async fn router(pool: PgPool, idle_timeout: Duration) -> Router {
+    let mut games = store::load_games(&pool, idle_timeout)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Problem loading games: {e}");
+            HashMap::new()
+        });
+    games
+        .entry(DEFAULT_GAME.to_string())
+        .or_insert_with(|| Data::new(Board::default(), false));
+    let games = Games {
+        games,
+        idle_timeout,
+        db: pool,
+    };
 
-    let data = Arc::new(RwLock::new(Data { board, rng }));
+    let games = Arc::new(RwLock::new(games));
 
     Router::new()
         .route("/12/board", get(get_board))
         .route("/12/reset", post(reset_board))
         .route("/12/place/:team/:column", post(place))
         .route("/12/random-board", get(random_board))
-        .with_state(data)
+        .route("/12/ai/:team", post(ai_place))
+        .route("/12/hint/:team", get(hint))
+        .route("/12/undo", post(undo))
+        .route("/12/history", get(history))
+        .route("/12/replay/:n", get(replay))
+        .route("/12/join/:team", post(join))
+        .route("/12/watch", get(watch))
+        .route("/12/stats", get(stats))
+        .route("/12/analyse", post(analyse))
+        .route("/12/games", post(create_game))
+        .route("/12/games/:id/board", get(get_board))
+        .route("/12/games/:id/reset", post(reset_board))
+        .route("/12/games/:id/place/:team/:column", post(place))
+        .route("/12/games/:id/random-board", get(random_board))
+        .route("/12/games/:id/ai/:team", post(ai_place))
+        .route("/12/games/:id/hint/:team", get(hint))
+        .route("/12/games/:id/undo", post(undo))
+        .route("/12/games/:id/history", get(history))
+        .route("/12/games/:id/replay/:n", get(replay))
+        .route("/12/games/:id/join/:team", post(join))
+        .route("/12/games/:id/watch", get(watch))
+        .with_state(games)
+}
+
+#[derive(Deserialize)]
+struct GameParams {
+    #[serde(default = "default_size")]
+    width: usize,
+    #[serde(default = "default_size")]
+    height: usize,
+    #[serde(default = "default_size")]
+    connect: usize,
+    #[serde(default)]
+    strict: bool,
+}
+
+impl Expected for GameParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "strict" => Some("true or false"),
+            _ => Some("positive whole number"),
+        }
+    }
+}
+
+fn default_size() -> usize {
+    4
+}
+
+#[axum::debug_handler]
+async fn create_game(
+    State(games): State<SharedGames>,
+    Query(params): Query<GameParams>,
+) -> impl IntoResponse {
+    let GameParams {
+        width,
+        height,
+        connect,
+        strict,
+    } = params;
+    if !(1..=MAX_BOARD_SIZE).contains(&width)
+        || !(1..=MAX_BOARD_SIZE).contains(&height)
+        || connect < 2
+        || connect > width.max(height)
+    {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let mut games = games.write().await;
+    let id = games.create(Board::new(width, height, connect), strict);
+    games.save(&id);
+    (StatusCode::CREATED, Json(json!({"id": id}))).into_response()
 }
 
 #[axum::debug_handler]
-async fn get_board(State(data): State<Arc<RwLock<Data>>>) -> impl IntoResponse {
-    data.read().await.board.to_string()
+async fn get_board(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let games = games.read().await;
+    let data = games.get(&id)?;
+    Ok(format.render(&data.board))
 }
 
 #[axum::debug_handler]
-async fn reset_board(State(data): State<Arc<RwLock<Data>>>) -> impl IntoResponse {
-    eprintln!("resetting");
-    let mut data = data.write_owned().await;
-    let rng = StdRng::seed_from_u64(RNG_SEED);
-    data.board = Board::default();
-    data.rng = rng;
-    data.board.to_string()
+async fn reset_board(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    token: PlayerToken,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    eprintln!("resetting game {id}");
+    let mut games = games.write().await;
+    let data = games.get_mut(&id)?;
+    // either player may reset a strict game
+    if let Err(denied) = data
+        .check_player(Tile::Cookie, &token)
+        .or_else(|_| data.check_player(Tile::Milk, &token))
+    {
+        return Ok(denied.into_response());
+    }
+    data.board = data.board.cleared();
+    data.reseed();
+    data.round += 1;
+    data.notify(&data.board);
+    let response = format.render(&data.board);
+    games.save(&id);
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct RandomParams {
+    seed: Option<u64>,
+    /// Fraction of the board to fill, from 0 to 1.
+    density: Option<f64>,
+}
+
+impl Expected for RandomParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "seed" => Some("whole number"),
+            "density" => Some("number from 0 to 1"),
+            _ => None,
+        }
+    }
 }
 
+/// Generate a random board with the game's dimensions. Plain requests draw
+/// it from the game's RNG, so the boards after a reset always come out the
+/// same. With a `seed` or `density`, it's generated from a seed of its own
+/// instead, random unless given, and returned in the `X-Board-Seed` header
+/// so the board can be generated again.
 #[axum::debug_handler]
-async fn random_board(State(data): State<Arc<RwLock<Data>>>) -> impl IntoResponse {
-    let mut data = data.write_owned().await;
-    Board::new_random(&mut data.rng).to_string()
+async fn random_board(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Query(params): Query<RandomParams>,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let density = params.density.unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&density) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut games = games.write().await;
+    let data = games.get_mut(&id)?;
+    if params.seed.is_none() && params.density.is_none() {
+        let board = data.next_random_board();
+        games.save(&id);
+        return Ok(format.render(&board));
+    }
+    let seed = params.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let board = &data.board;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let board = Board::new_random(&mut rng, board.width, board.height, board.connect, density);
+    let headers = [(HeaderName::from_static("x-board-seed"), seed.to_string())];
+    Ok((headers, format.render(&board)).into_response())
+}
+
+#[derive(Deserialize)]
+struct PlaceParams {
+    team: String,
+    column: u8,
 }
 
 #[axum::debug_handler]
 async fn place(
-    State(data): State<Arc<RwLock<Data>>>,
-    Path((team, column)): Path<(String, u8)>,
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Path(PlaceParams { team, column }): Path<PlaceParams>,
+    token: PlayerToken,
+    format: Format,
+) -> impl IntoResponse {
+    let Some(team) = Tile::from_team(&team) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let mut games = games.write().await;
+    let data = match games.get_mut(&id) {
+        Ok(data) => data,
+        Err(status) => return status.into_response(),
+    };
+    if let Err(denied) = data.check_turn(team, &token) {
+        return denied.into_response();
+    }
+    let column = column as usize;
+    if column == 0 || column > data.board.width {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    match data.board.place(team, column - 1) {
+        Ok(_) => {
+            data.notify(&data.board);
+            let response = format.render(&data.board);
+            games.save(&id);
+            response
+        }
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, format.render(&data.board)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TeamParams {
+    team: String,
+}
+
+#[derive(Deserialize)]
+struct AiParams {
+    depth: Option<u8>,
+}
+
+impl Expected for AiParams {
+    fn expected(_field: &str) -> Option<&'static str> {
+        Some("search depth, from 1 to 8")
+    }
+}
+
+/// Search the best column for `team` in a `Board::position`, without holding
+/// up the runtime. The depth is capped by the board's width, since wide
+/// boards are slow to search.
+async fn ai_column(board: Board, team: Tile, depth: u8) -> Option<usize> {
+    let depth = depth.min(ai::max_depth(board.width));
+    tokio::task::spawn_blocking(move || ai::best_column(&board, team, depth))
+        .await
+        .expect("AI search panicked")
+}
+
+/// Let the computer play a move for `team`. The search runs on a copy of the
+/// board, so other games aren't held up; if the game moved on meanwhile, the
+/// move isn't played.
+#[axum::debug_handler]
+async fn ai_place(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Path(TeamParams { team }): Path<TeamParams>,
+    Query(params): Query<AiParams>,
+    token: PlayerToken,
+    format: Format,
+) -> impl IntoResponse {
+    let Some(team) = Tile::from_team(&team) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let depth = params.depth.unwrap_or(ai::DEFAULT_DEPTH);
+    if depth > ai::MAX_DEPTH {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let (board, moves) = {
+        let mut games = games.write().await;
+        let data = match games.get_mut(&id) {
+            Ok(data) => data,
+            Err(status) => return status.into_response(),
+        };
+        if let Err(denied) = data.check_turn(team, &token) {
+            return denied.into_response();
+        }
+        (data.board.position(), data.board.moves.len())
+    };
+    let Some(column) = ai_column(board.clone(), team, depth).await else {
+        return (StatusCode::SERVICE_UNAVAILABLE, format.render(&board)).into_response();
+    };
+
+    let mut games = games.write().await;
+    let data = match games.get_mut(&id) {
+        Ok(data) => data,
+        Err(status) => return status.into_response(),
+    };
+    if data.board.moves.len() != moves || data.board.state != board.state {
+        return (StatusCode::CONFLICT, "Board changed during search\n").into_response();
+    }
+    match data.board.place(team, column) {
+        Ok(_) => {
+            data.notify(&data.board);
+            let response = format.render(&data.board);
+            games.save(&id);
+            response
+        }
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, format.render(&data.board)).into_response(),
+    }
+}
+
+/// Suggest a (1-based) column for `team`, without playing it.
+#[axum::debug_handler]
+async fn hint(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Path(TeamParams { team }): Path<TeamParams>,
+    Query(params): Query<AiParams>,
+    format: Format,
 ) -> impl IntoResponse {
-    let team = match team.as_str() {
-        "cookie" => Tile::Cookie,
-        "milk" => Tile::Milk,
-        _ => return StatusCode::BAD_REQUEST.into_response(),
+    let Some(team) = Tile::from_team(&team) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let depth = params.depth.unwrap_or(ai::DEFAULT_DEPTH);
+    if depth > ai::MAX_DEPTH {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let board = match games.read().await.get(&id) {
+        Ok(data) => data.board.position(),
+        Err(status) => return status.into_response(),
     };
-    if column == 0 || column > 4 {
+    match ai_column(board, team, depth).await {
+        Some(column) => match format {
+            Format::Text => (column + 1).to_string().into_response(),
+            Format::Json => Json(json!({"column": column + 1})).into_response(),
+        },
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+#[axum::debug_handler]
+async fn undo(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    token: PlayerToken,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let mut games = games.write().await;
+    let data = games.get_mut(&id)?;
+    // only the player who made the last move may take it back
+    if let Some(last) = data.board.moves.last() {
+        if let Err(denied) = data.check_player(last.team, &token) {
+            return Ok(denied.into_response());
+        }
+    }
+    match data.board.undo() {
+        Some(_) => {
+            data.notify(&data.board);
+            let response = format.render(&data.board);
+            games.save(&id);
+            Ok(response)
+        }
+        None => Ok((StatusCode::CONFLICT, format.render(&data.board)).into_response()),
+    }
+}
+
+#[axum::debug_handler]
+async fn history(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let games = games.read().await;
+    let moves = &games.get(&id)?.board.moves;
+    match format {
+        Format::Text => {
+            let mut output = String::new();
+            for (n, m) in moves.iter().enumerate() {
+                writeln!(output, "{}. {m}", n + 1).unwrap();
+            }
+            Ok(output.into_response())
+        }
+        Format::Json => Ok(Json(moves).into_response()),
+    }
+}
+
+/// Join a strict game as `team`, receiving the token needed to play for it.
+#[axum::debug_handler]
+async fn join(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Path(TeamParams { team }): Path<TeamParams>,
+) -> impl IntoResponse {
+    let Some(team) = Tile::from_team(&team) else {
         return StatusCode::BAD_REQUEST.into_response();
+    };
+    let mut games = games.write().await;
+    let data = match games.get_mut(&id) {
+        Ok(data) => data,
+        Err(status) => return status.into_response(),
+    };
+    match data.join(team) {
+        Ok(token) => {
+            games.save(&id);
+            Json(json!({"team": team, "token": token})).into_response()
+        }
+        Err(denied) => denied.into_response(),
+    }
+}
+
+/// Stream the board as server-sent events: the current one straight away,
+/// then every change to it.
+#[axum::debug_handler]
+async fn watch(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, StatusCode> {
+    let games = games.read().await;
+    let data = games.get(&id)?;
+    let current = BoardUpdate::from(&data.board);
+    // lagging subscribers skip the updates they missed
+    let updates = BroadcastStream::new(data.updates.subscribe()).filter_map(Result::ok);
+    let stream = tokio_stream::once(current)
+        .chain(updates)
+        .map(|update| Event::default().event("board").json_data(update));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Win, loss and draw totals per team over all finished games.
+#[axum::debug_handler]
+async fn stats(State(games): State<SharedGames>, format: Format) -> impl IntoResponse {
+    let db = games.read().await.db.clone();
+    let stats = match store::stats(&db).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Problem fetching stats: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let teams = [
+        (Tile::Cookie, stats.cookie_wins, stats.milk_wins),
+        (Tile::Milk, stats.milk_wins, stats.cookie_wins),
+    ];
+    match format {
+        Format::Text => {
+            let mut output = String::new();
+            for (team, wins, losses) in teams {
+                writeln!(
+                    output,
+                    "{team} wins: {wins}, losses: {losses}, draws: {}",
+                    stats.draws
+                )
+                .unwrap();
+            }
+            output.into_response()
+        }
+        Format::Json => {
+            let teams: serde_json::Map<_, _> = teams
+                .into_iter()
+                .map(|(team, wins, losses)| {
+                    let totals = json!({"wins": wins, "losses": losses, "draws": stats.draws});
+                    (team.name().to_string(), totals)
+                })
+                .collect();
+            Json(teams).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AnalyseParams {
+    /// Tiles in a line needed to win, for text boards.
+    connect: Option<usize>,
+    depth: Option<u8>,
+}
+
+impl Expected for AnalyseParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "connect" => Some("positive whole number"),
+            "depth" => Some("search depth, from 1 to 8"),
+            _ => None,
+        }
     }
-    let column = column - 1;
-    let mut data = data.write_owned().await;
-    match data.board.place(team, column as usize) {
-        Ok(_) => data.board.to_string().into_response(),
-        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, data.board.to_string()).into_response(),
+}
+
+/// Board submitted as JSON, in the shape `Format::Json` renders.
+#[derive(Deserialize)]
+struct BoardInput {
+    cells: Vec<Vec<Tile>>,
+    connect: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Analysis {
+    state: GameState,
+    winner: Option<Tile>,
+    /// Completed lines, as row-major cell indices from the top left.
+    winning_lines: Vec<Vec<usize>>,
+    turn: Option<Tile>,
+    /// Team that can force a win within `depth` moves, in ongoing games.
+    forced_winner: Option<Tile>,
+    depth: u8,
+}
+
+/// Analyse a board sent either as emoji text or as JSON.
+#[axum::debug_handler]
+async fn analyse(
+    Query(params): Query<AnalyseParams>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    let depth = params.depth.unwrap_or(ai::DEFAULT_DEPTH);
+    if depth > ai::MAX_DEPTH {
+        return StatusCode::BAD_REQUEST.into_response();
     }
+    let connect = params.connect.unwrap_or(4);
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let board = if content_type.is_some_and(|c| c.starts_with("application/json")) {
+        serde_json::from_str::<BoardInput>(&body)
+            .map_err(|e| e.to_string())
+            .and_then(|input| Board::from_cells(input.cells, input.connect.unwrap_or(connect)))
+    } else {
+        Board::parse(&body, connect)
+    };
+    let board = match board {
+        Ok(board) => board,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid board: {e}\n")).into_response()
+        }
+    };
+
+    // wide boards are slow to search
+    let depth = depth.min(ai::max_depth(board.width));
+    let (state, winner) = board.outcome();
+    let turn = board.turn();
+    let forced_winner = match turn {
+        Some(team) => {
+            let board = board.position();
+            tokio::task::spawn_blocking(move || ai::forced_winner(&board, team, depth))
+                .await
+                .expect("AI search panicked")
+        }
+        None => None,
+    };
+    Json(Analysis {
+        state,
+        winner,
+        winning_lines: board.winning_lines(),
+        turn,
+        forced_winner,
+        depth,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct ReplayParams {
+    n: usize,
+}
+
+/// Render the board as it stood after move `n`.
+#[axum::debug_handler]
+async fn replay(
+    State(games): State<SharedGames>,
+    GameId(id): GameId,
+    Path(ReplayParams { n }): Path<ReplayParams>,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let games = games.read().await;
+    let board = games
+        .get(&id)?
+        .board
+        .replay(n)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(format.render(&board))
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Tile {
     Empty,
     Cookie,
     Milk,
 }
 
+impl Tile {
+    fn from_team(team: &str) -> Option<Self> {
+        match team {
+            "cookie" => Some(Self::Cookie),
+            "milk" => Some(Self::Milk),
+            _ => None,
+        }
+    }
+
+    fn from_emoji(emoji: char) -> Option<Self> {
+        match emoji {
+            '⬛' => Some(Self::Empty),
+            '🍪' => Some(Self::Cookie),
+            '🥛' => Some(Self::Milk),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::Cookie => "cookie",
+            Self::Milk => "milk",
+        }
+    }
+
+    fn opponent(self) -> Self {
+        match self {
+            Self::Cookie => Self::Milk,
+            Self::Milk => Self::Cookie,
+            Self::Empty => Self::Empty,
+        }
+    }
+}
+
 impl Display for Tile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -91,83 +872,187 @@ impl Display for Tile {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Board {
+    width: usize,
+    height: usize,
+    /// Number of tiles in a line needed to win.
+    connect: usize,
     winner: Option<Tile>,
-    state: [Tile; 16],
+    /// Tiles in row-major order, starting from the top row.
+    state: Vec<Tile>,
+    /// Moves played through `place`, oldest first.
+    moves: Vec<Move>,
+}
+
+/// A single move in a game's history.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Move {
+    team: Tile,
+    /// 1-based, as passed to the `place` route.
+    column: usize,
+    /// 1-based, counted from the bottom of the board.
+    row: usize,
+    played_at: DateTime<Utc>,
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} column {}, row {} at {}",
+            self.team,
+            self.column,
+            self.row,
+            self.played_at.to_rfc3339()
+        )
+    }
 }
 
 impl Board {
+    fn new(width: usize, height: usize, connect: usize) -> Self {
+        Self {
+            width,
+            height,
+            connect,
+            winner: None,
+            state: vec![Tile::Empty; width * height],
+            moves: Vec::new(),
+        }
+    }
+
+    /// Empty board with the same dimensions.
+    fn cleared(&self) -> Self {
+        Self::new(self.width, self.height, self.connect)
+    }
+
+    /// The same position without its history, which is all a search needs.
+    fn position(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            moves: Vec::new(),
+            ..*self
+        }
+    }
+
+    /// Play a move, recording it in the board's history.
     fn place(&mut self, tile: Tile, column: usize) -> Result<(), String> {
-        // let current_state = self.state;
+        let row = self.drop_tile(tile, column)?;
+        self.moves.push(Move {
+            team: tile,
+            column: column + 1,
+            row: self.height - row,
+            played_at: Utc::now(),
+        });
+        Ok(())
+    }
+
+    /// Drop `tile` into `column` without recording history, returning the
+    /// (top-based) row it landed in.
+    fn drop_tile(&mut self, tile: Tile, column: usize) -> Result<usize, String> {
         if self.state[column] != Tile::Empty {
             return Err("tile not empty".to_string());
         }
         if self.winner.is_some() {
             return Err("game already over".to_string());
         }
-        for i in (0..4).rev() {
-            let spot = column + i * 4;
-            if self.state[spot] == Tile::Empty {
-                self.state[spot] = tile;
-                break;
-            }
-        }
+        let row = (0..self.height)
+            .rev()
+            .find(|row| self.state[column + row * self.width] == Tile::Empty)
+            .expect("top tile is empty");
+        self.state[column + row * self.width] = tile;
         self.check_winner();
-        Ok(())
+        Ok(row)
     }
 
-    fn check_winner(&mut self) {
-        eprintln!("checking winner");
-        // check first diagonal
-        let tile = self.state[0];
-        if tile != Tile::Empty
-            && tile == self.state[5]
-            && tile == self.state[10]
-            && tile == self.state[15]
-        {
-            self.winner = Some(tile);
-            return;
+    /// Take back the last move played through `place`.
+    fn undo(&mut self) -> Option<Move> {
+        let last = self.moves.pop()?;
+        let row = self.height - last.row;
+        self.state[(last.column - 1) + row * self.width] = Tile::Empty;
+        // no moves can be played once the game is over, so there was no
+        // winner before the last move
+        self.winner = None;
+        Some(last)
+    }
+
+    /// The board as it stood after its first `n` moves.
+    fn replay(&self, n: usize) -> Option<Self> {
+        self.cleared().with_moves(self.moves.get(..n)?)
+    }
+
+    /// Play `moves` on top of this board, keeping their timestamps.
+    fn with_moves(mut self, moves: &[Move]) -> Option<Self> {
+        for m in moves {
+            if m.column == 0 || m.column > self.width {
+                return None;
+            }
+            self.place(m.team, m.column - 1).ok()?;
+            self.moves
+                .last_mut()
+                .expect("move was just placed")
+                .played_at = m.played_at;
         }
+        Some(self)
+    }
 
-        // check second diagonal
-        let tile = self.state[3];
-        if tile != Tile::Empty
-            && tile == self.state[6]
-            && tile == self.state[9]
-            && tile == self.state[12]
-        {
-            self.winner = Some(tile);
-            return;
+    /// Every line of `connect` cells on the board, as indices into `state`.
+    ///
+    /// Lines are in the order they're checked for a winner: diagonals first,
+    /// then rows starting from the bottom, then columns.
+    fn lines(&self) -> Vec<Vec<usize>> {
+        let (width, height, connect) = (self.width, self.height, self.connect);
+        let index = |row: usize, column: usize| row * width + column;
+        let mut lines = Vec::new();
+
+        if connect <= width && connect <= height {
+            // diagonals going down and to the right
+            for row in 0..=height - connect {
+                for column in 0..=width - connect {
+                    lines.push((0..connect).map(|i| index(row + i, column + i)).collect());
+                }
+            }
+
+            // diagonals going down and to the left
+            for row in 0..=height - connect {
+                for column in connect - 1..width {
+                    lines.push((0..connect).map(|i| index(row + i, column - i)).collect());
+                }
+            }
         }
 
-        // check horizontal, starting from bottom row
-        for i in (0..4).rev() {
-            let tile = self.state[i * 4];
-            if tile != Tile::Empty
-                && tile == self.state[i * 4 + 1]
-                && tile == self.state[i * 4 + 2]
-                && tile == self.state[i * 4 + 3]
-            {
-                self.winner = Some(tile);
-                return;
+        // horizontal, starting from bottom row
+        if connect <= width {
+            for row in (0..height).rev() {
+                for column in 0..=width - connect {
+                    lines.push((0..connect).map(|i| index(row, column + i)).collect());
+                }
             }
         }
 
-        // check vertical
-        for i in 0..4 {
-            let tile = self.state[i];
-            #[allow(clippy::identity_op)] // allow the '4 * 1' below, to make it more clear
-            if tile != Tile::Empty
-                && tile == self.state[i + 4 * 1]
-                && tile == self.state[i + 4 * 2]
-                && tile == self.state[i + 4 * 3]
-            {
-                self.winner = Some(tile);
-                return;
+        // vertical
+        if connect <= height {
+            for column in 0..width {
+                for row in 0..=height - connect {
+                    lines.push((0..connect).map(|i| index(row + i, column)).collect());
+                }
             }
         }
 
+        lines
+    }
+
+    fn check_winner(&mut self) {
+        let winner = self.lines().into_iter().find_map(|line| {
+            let tile = self.state[line[0]];
+            let complete = tile != Tile::Empty && line.iter().all(|&i| self.state[i] == tile);
+            complete.then_some(tile)
+        });
+        if winner.is_some() {
+            self.winner = winner;
+            return;
+        }
+
         // check board full, no winner
         if !self.state.contains(&Tile::Empty) {
             // "Empty" winner means "no winner" (see Display impl for Board)
@@ -175,46 +1060,200 @@ impl Board {
         }
     }
 
-    fn new_random(rng: &mut StdRng) -> Self {
-        let mut board = Self {
-            winner: None,
-            state: [Tile::Milk; 16],
-        };
-        for i in 0..16 {
-            if rng.gen() {
-                board.state[i] = Tile::Cookie;
-            }
+    /// Every line completely filled by one team.
+    fn winning_lines(&self) -> Vec<Vec<usize>> {
+        self.lines()
+            .into_iter()
+            .filter(|line| {
+                let tile = self.state[line[0]];
+                tile != Tile::Empty && line.iter().all(|&i| self.state[i] == tile)
+            })
+            .collect()
+    }
+
+    fn outcome(&self) -> (GameState, Option<Tile>) {
+        match self.winner {
+            None => (GameState::Ongoing, None),
+            Some(Tile::Empty) => (GameState::Draw, None),
+            Some(tile) => (GameState::Won, Some(tile)),
+        }
+    }
+
+    fn count(&self, tile: Tile) -> usize {
+        self.state.iter().filter(|&&t| t == tile).count()
+    }
+
+    /// Team expected to move next, assuming cookie moves first. `None` once
+    /// the game is over.
+    fn turn(&self) -> Option<Tile> {
+        if self.winner.is_some() {
+            return None;
+        }
+        if self.count(Tile::Cookie) > self.count(Tile::Milk) {
+            Some(Tile::Milk)
+        } else {
+            Some(Tile::Cookie)
+        }
+    }
+
+    /// Random board with about `density` of its tiles filled, stacked from
+    /// the bottom of each column.
+    fn new_random(
+        rng: &mut StdRng,
+        width: usize,
+        height: usize,
+        connect: usize,
+        density: f64,
+    ) -> Self {
+        let mut board = Self::new(width, height, connect);
+        for tile in board.state.iter_mut() {
+            *tile = if rng.gen() { Tile::Cookie } else { Tile::Milk };
+        }
+
+        // take tiles off the top of random columns until enough are left
+        let keep = (density * board.state.len() as f64).round() as usize;
+        let mut heights = vec![height; width];
+        for _ in keep..board.state.len() {
+            let columns: Vec<usize> = (0..width).filter(|&c| heights[c] > 0).collect();
+            let column = columns[rng.gen_range(0..columns.len())];
+            let row = height - heights[column];
+            board.state[row * width + column] = Tile::Empty;
+            heights[column] -= 1;
         }
+
         board.check_winner();
         board
     }
 }
 
+impl Board {
+    /// Board from rows of tiles, starting from the top row. Tiles have to be
+    /// stacked from the bottom, as if they had been played.
+    fn from_cells(cells: Vec<Vec<Tile>>, connect: usize) -> Result<Self, String> {
+        let height = cells.len();
+        let width = cells.first().map_or(0, Vec::len);
+        if !(1..=MAX_BOARD_SIZE).contains(&width) || !(1..=MAX_BOARD_SIZE).contains(&height) {
+            return Err(format!(
+                "board must be 1 to {MAX_BOARD_SIZE} tiles wide and high"
+            ));
+        }
+        if cells.iter().any(|row| row.len() != width) {
+            return Err("rows must all be the same width".to_string());
+        }
+        if connect < 2 || connect > width.max(height) {
+            return Err(format!(
+                "can't connect {connect} on a {width}x{height} board"
+            ));
+        }
+
+        let mut board = Self::new(width, height, connect);
+        board.state = cells.into_iter().flatten().collect();
+        for (i, &tile) in board.state.iter().enumerate().skip(width) {
+            if tile == Tile::Empty && board.state[i - width] != Tile::Empty {
+                return Err(format!("floating tile above row {}", i / width + 1));
+            }
+        }
+        board.check_winner();
+        Ok(board)
+    }
+
+    /// Parse a board in the format of its `Display` impl. Trailing lines
+    /// after the bottom border, like the winner, are ignored.
+    fn parse(text: &str, connect: usize) -> Result<Self, String> {
+        let mut cells = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let inner = line
+                .strip_prefix('⬜')
+                .and_then(|line| line.strip_suffix('⬜'))
+                .ok_or_else(|| format!("missing wall in line {line:?}"))?;
+            // bottom border
+            if inner.chars().all(|c| c == '⬜') {
+                return Self::from_cells(cells, connect);
+            }
+            let row = inner
+                .chars()
+                .map(|c| Tile::from_emoji(c).ok_or_else(|| format!("unknown tile {c:?}")))
+                .collect::<Result<_, _>>()?;
+            cells.push(row);
+        }
+        Err("missing bottom border".to_string())
+    }
+}
+
 impl Default for Board {
     fn default() -> Self {
-        let state = [Tile::Empty; 16];
+        Self::new(4, 4, 4)
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum GameState {
+    Ongoing,
+    Won,
+    Draw,
+}
+
+/// JSON representation of a board.
+#[derive(Serialize)]
+struct BoardView {
+    width: usize,
+    height: usize,
+    connect: usize,
+    /// Rows of tiles, starting from the top row.
+    cells: Vec<Vec<Tile>>,
+    state: GameState,
+    winner: Option<Tile>,
+    turn: Option<Tile>,
+    moves: usize,
+}
+
+impl From<&Board> for BoardView {
+    fn from(board: &Board) -> Self {
+        let (state, winner) = board.outcome();
         Self {
-            winner: None,
+            width: board.width,
+            height: board.height,
+            connect: board.connect,
+            cells: board.state.chunks(board.width).map(<[_]>::to_vec).collect(),
+            state,
+            winner,
+            turn: board.turn(),
+            moves: board.count(Tile::Cookie) + board.count(Tile::Milk),
+        }
+    }
+}
+
+/// Event sent to `/12/watch` subscribers.
+#[derive(Clone, Serialize)]
+struct BoardUpdate {
+    /// The board rendered as emoji text.
+    board: String,
+    state: GameState,
+    winner: Option<Tile>,
+}
+
+impl From<&Board> for BoardUpdate {
+    fn from(board: &Board) -> Self {
+        let (state, winner) = board.outcome();
+        Self {
+            board: board.to_string(),
             state,
+            winner,
         }
     }
 }
 
 impl Display for Board {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut x = 0_usize;
-        for _ in 0..4 {
-            writeln!(
-                f,
-                "⬜{}{}{}{}⬜",
-                self.state[x],
-                self.state[x + 1],
-                self.state[x + 2],
-                self.state[x + 3]
-            )?;
-            x += 4;
-        }
-        writeln!(f, "⬜⬜⬜⬜⬜⬜")?;
+        for row in self.state.chunks(self.width) {
+            write!(f, "⬜")?;
+            for tile in row {
+                write!(f, "{tile}")?;
+            }
+            writeln!(f, "⬜")?;
+        }
+        writeln!(f, "{}", "⬜".repeat(self.width + 2))?;
         if let Some(winner) = self.winner {
             match winner {
                 // "Empty" winner means "no winner" (board full)
@@ -225,3 +1264,137 @@ impl Display for Board {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(text: &str, connect: usize) -> Board {
+        Board::parse(text, connect).unwrap()
+    }
+
+    /// Row and column of every cell of a line.
+    fn cells(board: &Board, line: &[usize]) -> Vec<(usize, usize)> {
+        line.iter()
+            .map(|&i| (i / board.width, i % board.width))
+            .collect()
+    }
+
+    #[test]
+    fn parse_display_round_trip() {
+        let text = "\
+⬜⬛⬛⬛⬛⬛⬜
+⬜⬛⬛⬛⬛🥛⬜
+⬜⬛🍪⬛🥛🍪⬜
+⬜🍪🥛⬛🍪🥛⬜
+⬜⬜⬜⬜⬜⬜⬜
+";
+        let parsed = board(text, 4);
+        assert_eq!((parsed.width, parsed.height), (5, 4));
+        assert_eq!(parsed.winner, None);
+        assert_eq!(parsed.to_string(), text);
+
+        let won = "\
+⬜⬛⬛⬛⬜
+⬜🥛⬛⬛⬜
+⬜🍪🍪🍪⬜
+⬜⬜⬜⬜⬜
+🍪 wins!
+";
+        let parsed = board(won, 3);
+        assert_eq!(parsed.winner, Some(Tile::Cookie));
+        assert_eq!(parsed.to_string(), won);
+        assert_eq!(board(&parsed.to_string(), 3).state, parsed.state);
+    }
+
+    #[test]
+    fn floating_tiles_are_rejected() {
+        let text = "\
+⬜⬛🍪⬜
+⬜⬛⬛⬜
+⬜🥛🍪⬜
+⬜⬜⬜⬜
+";
+        let error = Board::parse(text, 2).unwrap_err();
+        assert!(error.contains("floating"), "{error}");
+    }
+
+    #[test]
+    fn uneven_rows_are_rejected() {
+        let cells = vec![
+            vec![Tile::Empty, Tile::Empty, Tile::Empty],
+            vec![Tile::Cookie, Tile::Milk],
+        ];
+        let error = Board::from_cells(cells, 2).unwrap_err();
+        assert_eq!(error, "rows must all be the same width");
+
+        let text = "\
+⬜⬛⬛⬛⬜
+⬜🍪🥛⬜
+⬜⬜⬜⬜⬜
+";
+        assert!(Board::parse(text, 2).is_err());
+    }
+
+    #[test]
+    fn winning_lines_lists_every_complete_line() {
+        let text = "\
+⬜⬛⬛⬛⬛⬛⬜
+⬜⬛⬛⬛⬛⬛⬜
+⬜🥛🥛⬛⬛⬛⬜
+⬜🍪🍪🍪🍪🍪⬜
+⬜⬜⬜⬜⬜⬜⬜
+";
+        let parsed = board(text, 4);
+        assert_eq!(parsed.winner, Some(Tile::Cookie));
+        let lines: Vec<_> = parsed
+            .winning_lines()
+            .iter()
+            .map(|line| cells(&parsed, line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                vec![(3, 0), (3, 1), (3, 2), (3, 3)],
+                vec![(3, 1), (3, 2), (3, 3), (3, 4)],
+            ]
+        );
+        assert!(board(&text.replace(['🍪', '🥛'], "⬛"), 4)
+            .winning_lines()
+            .is_empty());
+    }
+
+    #[test]
+    fn lines_stay_on_non_square_boards() {
+        for (width, height, connect, diagonals, total) in [
+            (5, 3, 3, 6, 20),
+            (3, 5, 3, 6, 20),
+            (7, 2, 3, 0, 10),
+            (2, 7, 3, 0, 10),
+        ] {
+            let board = Board::new(width, height, connect);
+            let lines = board.lines();
+            assert_eq!(lines.len(), total, "{width}x{height}");
+
+            let mut diagonal = 0;
+            for line in &lines {
+                let cells = cells(&board, line);
+                assert_eq!(cells.len(), connect);
+                let step = |a: usize, b: usize| b as isize - a as isize;
+                let (dr, dc) = (step(cells[0].0, cells[1].0), step(cells[0].1, cells[1].1));
+                assert!(dr.abs() <= 1 && dc.abs() <= 1 && (dr, dc) != (0, 0));
+                for pair in cells.windows(2) {
+                    assert_eq!(
+                        (step(pair[0].0, pair[1].0), step(pair[0].1, pair[1].1)),
+                        (dr, dc),
+                        "{width}x{height} line {cells:?} wraps"
+                    );
+                }
+                if dr != 0 && dc != 0 {
+                    diagonal += 1;
+                }
+            }
+            assert_eq!(diagonal, diagonals, "{width}x{height}");
+        }
+    }
+}
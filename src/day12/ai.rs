@@ -0,0 +1,169 @@
+//! Computer opponent: picks a column using minimax with alpha-beta pruning.
+
+use super::{Board, Tile};
+
+pub const DEFAULT_DEPTH: u8 = 5;
+pub const MAX_DEPTH: u8 = 8;
+
+/// Score of a won position, before adjusting for how soon it's reached.
+const WIN_SCORE: i32 = 1_000_000;
+/// Positions a search may visit, about what `MAX_DEPTH` takes on a standard
+/// 7-column board.
+const MAX_POSITIONS: u64 = 7_u64.pow(MAX_DEPTH as u32);
+
+/// Deepest search allowed on a board `width` columns wide, since every
+/// column multiplies the positions searched at each level.
+pub fn max_depth(width: usize) -> u8 {
+    (1..=MAX_DEPTH)
+        .rev()
+        .find(|&depth| {
+            (width as u64)
+                .checked_pow(depth as u32)
+                .is_some_and(|positions| positions <= MAX_POSITIONS)
+        })
+        .unwrap_or(1)
+}
+
+/// The board's lines, worked out once per search rather than per position.
+struct Lines {
+    all: Vec<Vec<usize>>,
+    /// Indices into `all` of the lines through each cell.
+    through: Vec<Vec<usize>>,
+}
+
+impl Lines {
+    fn new(board: &Board) -> Self {
+        let all = board.lines();
+        let mut through = vec![Vec::new(); board.state.len()];
+        for (n, line) in all.iter().enumerate() {
+            for &cell in line {
+                through[cell].push(n);
+            }
+        }
+        Self { all, through }
+    }
+}
+
+/// `board` with `tile` dropped into `column`, like `Board::drop_tile` but
+/// only checking the lines through the new tile, and without history.
+fn play(board: &Board, tile: Tile, column: usize, lines: &Lines) -> Option<Board> {
+    if board.winner.is_some() || board.state[column] != Tile::Empty {
+        return None;
+    }
+    let row = (0..board.height)
+        .rev()
+        .find(|row| board.state[column + row * board.width] == Tile::Empty)?;
+    let cell = column + row * board.width;
+    let mut next = board.clone();
+    next.state[cell] = tile;
+    let won = lines.through[cell]
+        .iter()
+        .any(|&n| lines.all[n].iter().all(|&i| next.state[i] == tile));
+    if won {
+        next.winner = Some(tile);
+    } else if !next.state.contains(&Tile::Empty) {
+        next.winner = Some(Tile::Empty);
+    }
+    Some(next)
+}
+
+/// Best column (0-based) for `tile` to play, searching `depth` moves ahead.
+/// Returns `None` when the game is over or the board is full. The board
+/// should be a `Board::position`, since it's copied for every position.
+pub fn best_column(board: &Board, tile: Tile, depth: u8) -> Option<usize> {
+    let depth = depth.max(1);
+    let lines = Lines::new(board);
+    let mut alpha = -i32::MAX;
+    let mut best = None;
+    for column in moves(board) {
+        let Some(next) = play(board, tile, column, &lines) else {
+            continue;
+        };
+        let score = -negamax(&next, tile.opponent(), depth - 1, -i32::MAX, -alpha, &lines);
+        if best.is_none() || score > alpha {
+            alpha = score;
+            best = Some(column);
+        }
+    }
+    best
+}
+
+/// Team that can force a win within `depth` moves, with `tile` to move next.
+pub fn forced_winner(board: &Board, tile: Tile, depth: u8) -> Option<Tile> {
+    let lines = Lines::new(board);
+    let score = negamax(board, tile, depth, -i32::MAX, i32::MAX, &lines);
+    if score >= WIN_SCORE {
+        Some(tile)
+    } else if score <= -WIN_SCORE {
+        Some(tile.opponent())
+    } else {
+        None
+    }
+}
+
+/// Columns that can still be played, center ones first so that pruning
+/// kicks in early.
+fn moves(board: &Board) -> Vec<usize> {
+    if board.winner.is_some() {
+        return Vec::new();
+    }
+    let center = board.width / 2;
+    let mut columns: Vec<usize> = (0..board.width)
+        .filter(|&column| board.state[column] == Tile::Empty)
+        .collect();
+    columns.sort_by_key(|&column| column.abs_diff(center));
+    columns
+}
+
+/// Score of `board` from the point of view of `tile`, which is to move next.
+fn negamax(board: &Board, tile: Tile, depth: u8, mut alpha: i32, beta: i32, lines: &Lines) -> i32 {
+    match board.winner {
+        Some(Tile::Empty) => return 0,
+        // prefer quicker wins and slower losses
+        Some(winner) if winner == tile => return WIN_SCORE + depth as i32,
+        Some(_) => return -WIN_SCORE - depth as i32,
+        None => {}
+    }
+    if depth == 0 {
+        return evaluate(board, tile, &lines.all);
+    }
+
+    let mut best = -i32::MAX;
+    for column in moves(board) {
+        let Some(next) = play(board, tile, column, lines) else {
+            continue;
+        };
+        let score = -negamax(&next, tile.opponent(), depth - 1, -beta, -alpha, lines);
+        best = best.max(score);
+        alpha = alpha.max(score);
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+/// Heuristic score for a position that isn't decided yet: every line that
+/// only one team has tiles in counts for that team, more so the fuller it is.
+fn evaluate(board: &Board, tile: Tile, lines: &[Vec<usize>]) -> i32 {
+    let opponent = tile.opponent();
+    lines
+        .iter()
+        .map(|line| {
+            let own = line.iter().filter(|&&i| board.state[i] == tile).count();
+            let theirs = line.iter().filter(|&&i| board.state[i] == opponent).count();
+            match (own, theirs) {
+                (0, 0) => 0,
+                (own, 0) => line_score(own, board.connect),
+                (0, theirs) => -line_score(theirs, board.connect),
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+fn line_score(tiles: usize, connect: usize) -> i32 {
+    // one tile short of winning is worth far more than a lone tile
+    let missing = (connect - tiles) as u32;
+    1000 / 10_i32.pow(missing.min(3))
+}
@@ -0,0 +1,279 @@
+//! Postgres persistence for games, so they survive restarts.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{Board, Data, Move, Tile, DEFAULT_GAME};
+use sqlx::{
+    query, query_as,
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use tokio::sync::mpsc;
+
+struct GameRow {
+    id: String,
+    width: i32,
+    height: i32,
+    connect: i32,
+    strict: bool,
+    cookie_token: Option<String>,
+    milk_token: Option<String>,
+    rng_seed: i64,
+    random_boards: i32,
+    round: i32,
+    idle_secs: f64,
+}
+
+struct MoveRow {
+    team: String,
+    board_column: i32,
+    played_at: DateTime<Utc>,
+}
+
+/// Win, loss and draw totals over all finished games.
+pub struct Stats {
+    pub cookie_wins: i64,
+    pub milk_wins: i64,
+    pub draws: i64,
+}
+
+/// A game as it's stored, taken while the game is locked so it can be
+/// written after.
+pub struct Snapshot {
+    width: usize,
+    height: usize,
+    connect: usize,
+    strict: bool,
+    cookie_token: Option<String>,
+    milk_token: Option<String>,
+    rng_seed: u64,
+    random_boards: u32,
+    round: u32,
+    winner: Option<Tile>,
+    moves: Vec<Move>,
+}
+
+impl Snapshot {
+    pub fn of(data: &Data) -> Self {
+        let board = &data.board;
+        Self {
+            width: board.width,
+            height: board.height,
+            connect: board.connect,
+            strict: data.strict,
+            cookie_token: data.tokens.get(&Tile::Cookie).cloned(),
+            milk_token: data.tokens.get(&Tile::Milk).cloned(),
+            rng_seed: data.rng_seed,
+            random_boards: data.random_boards,
+            round: data.round,
+            winner: board.winner,
+            moves: board.moves.clone(),
+        }
+    }
+}
+
+/// Start writing snapshots of game `id`, in the order they're sent. The
+/// writer stops once the game is dropped along with the sender.
+pub fn writer(db: PgPool, id: String) -> mpsc::UnboundedSender<Snapshot> {
+    let (sender, mut snapshots) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // moves known to be stored, so only the ones after them are written;
+        // unknown at first and after a failed write
+        let mut stored = None;
+        while let Some(snapshot) = snapshots.recv().await {
+            match save_game(&db, &id, &snapshot, stored.as_deref()).await {
+                Ok(()) => stored = Some(snapshot.moves),
+                Err(e) => {
+                    eprintln!("Problem saving game {id}: {e}");
+                    stored = None;
+                }
+            }
+        }
+    });
+    sender
+}
+
+/// Write a snapshot of a game over what was stored before. Of its moves,
+/// only those that differ from `stored` are written.
+async fn save_game(
+    db: &PgPool,
+    id: &str,
+    game: &Snapshot,
+    stored: Option<&[Move]>,
+) -> sqlx::Result<()> {
+    let mut tx = db.begin().await?;
+    query!(
+        r#"INSERT INTO games (id, width, height, connect, strict, cookie_token, milk_token, rng_seed, random_boards, round)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (id) DO UPDATE SET cookie_token = $6, milk_token = $7, rng_seed = $8, random_boards = $9, round = $10, updated_at = CURRENT_TIMESTAMP"#,
+        id,
+        game.width as i32,
+        game.height as i32,
+        game.connect as i32,
+        game.strict,
+        game.cookie_token,
+        game.milk_token,
+        game.rng_seed as i64,
+        game.random_boards as i32,
+        game.round as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // moves taken back or replaced by a reset go, new ones are added
+    let kept = stored.map_or(0, |stored| {
+        stored
+            .iter()
+            .zip(&game.moves)
+            .take_while(|(stored, m)| stored == m)
+            .count()
+    });
+    query!(
+        r#"DELETE FROM moves WHERE game_id = $1 AND n >= $2"#,
+        id,
+        kept as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+    for (n, m) in game.moves.iter().enumerate().skip(kept) {
+        query!(
+            r#"INSERT INTO moves (game_id, n, team, board_column, board_row, played_at) VALUES ($1, $2, $3, $4, $5, $6)"#,
+            id,
+            n as i32,
+            m.team.name(),
+            m.column as i32,
+            m.row as i32,
+            m.played_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    match game.winner {
+        Some(winner) => {
+            let winner = match winner {
+                Tile::Empty => "draw",
+                tile => tile.name(),
+            };
+            query!(
+                r#"INSERT INTO game_results (game_id, round, winner) VALUES ($1, $2, $3)
+                ON CONFLICT (game_id, round) DO UPDATE SET winner = $3"#,
+                id,
+                game.round as i32,
+                winner,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        // the winning move may have been taken back
+        None => {
+            query!(
+                r#"DELETE FROM game_results WHERE game_id = $1 AND round = $2"#,
+                id,
+                game.round as i32,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await
+}
+
+/// Restore every game that changed within `idle_timeout`, deleting the rest.
+pub async fn load_games(
+    db: &PgPool,
+    idle_timeout: Duration,
+) -> sqlx::Result<HashMap<String, Data>> {
+    query!(
+        r#"DELETE FROM games WHERE id <> $1 AND updated_at < CURRENT_TIMESTAMP - make_interval(secs => $2)"#,
+        DEFAULT_GAME,
+        idle_timeout.as_secs_f64(),
+    )
+    .execute(db)
+    .await?;
+
+    let rows = query_as!(
+        GameRow,
+        r#"SELECT id, width, height, connect, strict, cookie_token, milk_token, rng_seed, random_boards, round,
+        EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - updated_at)::FLOAT8 AS "idle_secs!"
+        FROM games"#,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut games = HashMap::new();
+    for row in rows {
+        let moves = query_as!(
+            MoveRow,
+            r#"SELECT team, board_column, played_at FROM moves WHERE game_id = $1 ORDER BY n"#,
+            row.id,
+        )
+        .fetch_all(db)
+        .await?;
+        match restore(row, moves) {
+            Some((id, data)) => {
+                games.insert(id, data);
+            }
+            None => eprintln!("Skipping game with invalid stored moves"),
+        }
+    }
+    Ok(games)
+}
+
+fn restore(row: GameRow, moves: Vec<MoveRow>) -> Option<(String, Data)> {
+    let moves = moves
+        .into_iter()
+        .map(|m| {
+            Some(Move {
+                team: Tile::from_team(&m.team)?,
+                column: m.board_column as usize,
+                row: 0,
+                played_at: m.played_at,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let board = Board::new(
+        row.width as usize,
+        row.height as usize,
+        row.connect as usize,
+    )
+    .with_moves(&moves)?;
+
+    let mut data = Data::new(board, row.strict);
+    for (team, token) in [
+        (Tile::Cookie, row.cookie_token),
+        (Tile::Milk, row.milk_token),
+    ] {
+        if let Some(token) = token {
+            data.tokens.insert(team, token);
+        }
+    }
+    let idle_for = Duration::try_from_secs_f64(row.idle_secs).unwrap_or_default();
+    if let Some(last_active) = Instant::now().checked_sub(idle_for) {
+        data.last_active = last_active;
+    }
+    data.round = row.round as u32;
+    data.rng_seed = row.rng_seed as u64;
+    data.reseed();
+    for _ in 0..row.random_boards {
+        data.next_random_board();
+    }
+    Some((row.id, data))
+}
+
+pub async fn stats(db: &PgPool) -> sqlx::Result<Stats> {
+    query_as!(
+        Stats,
+        r#"SELECT
+            count(*) FILTER (WHERE winner = 'cookie') AS "cookie_wins!",
+            count(*) FILTER (WHERE winner = 'milk') AS "milk_wins!",
+            count(*) FILTER (WHERE winner = 'draw') AS "draws!"
+        FROM game_results"#,
+    )
+    .fetch_one(db)
+    .await
+}
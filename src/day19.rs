@@ -1,7 +1,7 @@
 use std::{collections::HashMap, ops::Not, sync::Arc};
 
 use axum::{
-    extract::{Path, Query, State},
+    extract::{Path, State},
     http::StatusCode,
     response::IntoResponse,
     routing::{delete, get, post, put},
@@ -19,6 +19,8 @@ use sqlx::{
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+use crate::extract::{Expected, Query, ValidPayload};
+
 #[derive(Clone)]
 struct Data {
     db: PgPool,
@@ -50,7 +52,10 @@ async fn reset(State(data): State<Data>) -> impl IntoResponse {
 }
 
 #[axum::debug_handler]
-async fn create(State(data): State<Data>, Json(draft): Json<Draft>) -> impl IntoResponse {
+async fn create(
+    State(data): State<Data>,
+    ValidPayload(draft): ValidPayload<Draft>,
+) -> impl IntoResponse {
     let id = Uuid::new_v4();
     match query_as!(
         Quote,
@@ -92,7 +97,7 @@ async fn remove(State(data): State<Data>, Path(id): Path<Uuid>) -> impl IntoResp
 async fn update(
     State(data): State<Data>,
     Path(id): Path<Uuid>,
-    Json(draft): Json<Draft>,
+    ValidPayload(draft): ValidPayload<Draft>,
 ) -> impl IntoResponse {
     match query_as!(
         Quote,
@@ -210,3 +215,5 @@ struct Draft {
 struct ListParams {
     token: Option<String>,
 }
+
+impl Expected for ListParams {}
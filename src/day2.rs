@@ -1,7 +1,35 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{
+    convert::Infallible,
+    fmt::{Display, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use axum::{extract::Query, response::IntoResponse, routing::get, Router};
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
 use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::extract::{Expected, Format, Media, Query};
+use cipher::{AddSub, AddressCipher, Mode};
+use mixed::{Conversion, Family};
+
+mod cipher;
+mod mixed;
+
+/// Addresses listed per page when a block is returned address by address.
+const PAGE_SIZE: u64 = 256;
+
+/// Longest line a line by line batch may have.
+const MAX_BATCH_LINE: usize = 64 * 1024;
 
 pub fn router() -> Router {
     Router::new()
@@ -9,76 +37,528 @@ pub fn router() -> Router {
         .route("/2/key", get(task2))
         .route("/2/v6/dest", get(task3))
         .route("/2/v6/key", get(task4))
+        .route("/2/any/dest", get(any_dest))
+        .route("/2/any/key", get(any_key))
+        .route("/2/batch", post(batch))
+}
+
+/// IPv4 address or CIDR block, like `10.0.0.1` or `10.0.0.0/24`. A plain
+/// address is a block of one.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Net {
+    fn network(&self) -> Ipv4Addr {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        Ipv4Addr::from(u32::from(self.addr) & mask)
+    }
+
+    fn size(&self) -> u64 {
+        1 << (32 - self.prefix)
+    }
+
+    fn nth(&self, n: u64) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network()) + n as u32)
+    }
+
+    /// The block all addresses land in when `key` is added to them, if they
+    /// form one. Octets wrap on their own, so the key must not move the
+    /// addresses across the block boundary within a partially covered octet.
+    fn with_key(&self, key: Ipv4Addr) -> Option<Self> {
+        let host_bits = (32 - self.prefix as u32) % 8;
+        let partial_octet = (self.prefix / 8) as usize;
+        if host_bits != 0 && key.octets()[partial_octet] & ((1 << host_bits) - 1) != 0 {
+            return None;
+        }
+        let net = Self {
+            addr: add_key(self.network(), key),
+            prefix: self.prefix,
+        };
+        Some(Self {
+            addr: net.network(),
+            ..net
+        })
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let prefix = prefix
+                    .parse()
+                    .ok()
+                    .filter(|&prefix| prefix <= 32)
+                    .ok_or_else(|| format!("invalid prefix length: {prefix}"))?;
+                (addr, prefix)
+            }
+            None => (s, 32),
+        };
+        let addr = addr.parse().map_err(|e| format!("{e}: {addr}"))?;
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Ipv4Net {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Ipv4Net {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
 }
 
 #[derive(Deserialize)]
 struct DestParams {
-    from: Ipv4Addr,
+    from: Ipv4Net,
     key: Ipv4Addr,
+    #[serde(default = "default_mode_v4")]
+    mode: Mode,
+    /// List every destination address of a block, rather than the block.
+    #[serde(default)]
+    list: bool,
+    #[serde(default = "first_page")]
+    page: u64,
+}
+
+const IPV4: &str = "IPv4 address, like 1.2.3.4";
+const IPV4_NET: &str = "IPv4 address or CIDR block, like 10.0.0.1 or 10.0.0.0/24";
+const IPV6: &str = "IPv6 address, like 2001:db8::1";
+const IP: &str = "IPv4 or IPv6 address";
+const MODE: &str = "one of add, xor, rotate, permute";
+
+impl Expected for DestParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" => Some(IPV4_NET),
+            "key" => Some(IPV4),
+            "mode" => Some(MODE),
+            "list" => Some("true or false"),
+            "page" => Some("page number, starting from 1"),
+            _ => None,
+        }
+    }
+}
+
+fn default_mode_v4() -> Mode {
+    Mode::Add
+}
+
+fn default_mode_v6() -> Mode {
+    Mode::Xor
+}
+
+fn first_page() -> u64 {
+    1
+}
+
+/// A single result, as plain text or as a JSON object with one `name` field.
+fn respond(format: Format, name: &str, value: impl Display) -> Response {
+    match format {
+        Format::Text => value.to_string().into_response(),
+        Format::Json => Json(json!({ name: value.to_string() })).into_response(),
+    }
+}
+
+/// Like `respond`, adding how the addresses were converted: a second line in
+/// text, a `conversion` field in JSON.
+fn respond_converted(
+    format: Format,
+    name: &str,
+    value: impl Display,
+    conversion: Option<Conversion>,
+) -> Response {
+    match (format, conversion) {
+        (Format::Text, Some(conversion)) => format!("{value}\n{conversion}").into_response(),
+        (Format::Text, None) => value.to_string().into_response(),
+        (Format::Json, conversion) => {
+            Json(json!({ name: value.to_string(), "conversion": conversion })).into_response()
+        }
+    }
+}
+
+fn reject(format: Format, message: impl Display) -> Response {
+    match format {
+        Format::Text => (StatusCode::BAD_REQUEST, format!("{message}\n")).into_response(),
+        Format::Json => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": message.to_string()})),
+        )
+            .into_response(),
+    }
 }
 
 #[axum::debug_handler]
-async fn task1(Query(params): Query<DestParams>) -> impl IntoResponse {
-    let from = params.from.octets();
-    let key = params.key.octets();
-    let dest: Vec<u8> = from
-        .iter()
-        .zip(key)
-        .map(|(a, b)| a.wrapping_add(b))
-        .collect();
-    let dest: [u8; 4] = dest.try_into().unwrap();
-    let dest = Ipv4Addr::from(dest);
-    format!("{dest}").into_response()
+async fn task1(format: Format, Query(params): Query<DestParams>) -> impl IntoResponse {
+    let (from, key, mode) = (params.from, params.key, params.mode);
+    let dest = |from: Ipv4Addr| mode.dest(from.into(), key.into()).expect("same family");
+    if from.prefix == 32 {
+        return respond(format, "dest", dest(from.addr));
+    }
+    if !params.list && matches!(mode, Mode::Add) {
+        if let Some(dest) = from.with_key(key) {
+            return respond(format, "dest", dest);
+        }
+    }
+
+    // blocks that don't map onto a block are listed address by address
+    let pages = from.size().div_ceil(PAGE_SIZE);
+    if params.page == 0 || params.page > pages {
+        return reject(format, format!("Page must be 1 to {pages}"));
+    }
+    let start = (params.page - 1) * PAGE_SIZE;
+    let end = (start + PAGE_SIZE).min(from.size());
+    let dests = (start..end).map(|n| dest(from.nth(n)));
+    let next_page = (params.page < pages).then_some(params.page + 1);
+    let headers =
+        next_page.map(|page| [(HeaderName::from_static("x-next-page"), page.to_string())]);
+    match format {
+        Format::Text => {
+            let mut output = String::new();
+            for dest in dests {
+                writeln!(output, "{dest}").unwrap();
+            }
+            (headers, output).into_response()
+        }
+        Format::Json => {
+            let dests: Vec<_> = dests.collect();
+            (
+                headers,
+                Json(json!({"dests": dests, "next_page": next_page})),
+            )
+                .into_response()
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct KeyParams {
-    from: Ipv4Addr,
-    to: Ipv4Addr,
+    from: Ipv4Net,
+    to: Ipv4Net,
+    #[serde(default = "default_mode_v4")]
+    mode: Mode,
+}
+
+impl Expected for KeyParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" | "to" => Some(IPV4_NET),
+            "mode" => Some(MODE),
+            _ => None,
+        }
+    }
 }
 
 #[axum::debug_handler]
-async fn task2(Query(params): Query<KeyParams>) -> impl IntoResponse {
-    let from = params.from.octets();
-    let to = params.to.octets();
-    let key: Vec<u8> = to
-        .iter()
-        .zip(from)
-        .map(|(a, b)| a.wrapping_sub(b))
-        .collect();
-    let key: [u8; 4] = key.try_into().unwrap();
-    let key = Ipv4Addr::from(key);
-    format!("{key}").into_response()
+async fn task2(format: Format, Query(params): Query<KeyParams>) -> impl IntoResponse {
+    let (from, to) = (params.from, params.to);
+    // for blocks, the key that maps one network address onto the other
+    if from.prefix != 32 || to.prefix != 32 {
+        if !matches!(params.mode, Mode::Add) {
+            return reject(format, "Blocks need the add mode");
+        }
+        if from.prefix != to.prefix {
+            return reject(format, "Blocks must be the same size");
+        }
+    }
+    match params.mode.key(from.network().into(), to.network().into()) {
+        Ok(key) => respond(format, "key", key),
+        Err(e) => reject(format, e),
+    }
 }
 
 #[derive(Deserialize)]
 struct DestParams6 {
     from: Ipv6Addr,
     key: Ipv6Addr,
+    #[serde(default = "default_mode_v6")]
+    mode: Mode,
+}
+
+impl Expected for DestParams6 {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" | "key" => Some(IPV6),
+            "mode" => Some(MODE),
+            _ => None,
+        }
+    }
 }
 
 #[axum::debug_handler]
-async fn task3(Query(params): Query<DestParams6>) -> impl IntoResponse {
-    let dest = xor_ipv6(params.from, params.key);
-    format!("{dest}").into_response()
+async fn task3(format: Format, Query(params): Query<DestParams6>) -> impl IntoResponse {
+    let dest = params
+        .mode
+        .dest(params.from.into(), params.key.into())
+        .expect("same family");
+    respond(format, "dest", dest)
 }
 
 #[derive(Deserialize)]
 struct KeyParams6 {
     from: Ipv6Addr,
     to: Ipv6Addr,
+    #[serde(default = "default_mode_v6")]
+    mode: Mode,
+}
+
+impl Expected for KeyParams6 {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" | "to" => Some(IPV6),
+            "mode" => Some(MODE),
+            _ => None,
+        }
+    }
+}
+
+#[axum::debug_handler]
+async fn task4(format: Format, Query(params): Query<KeyParams6>) -> impl IntoResponse {
+    match params.mode.key(params.from.into(), params.to.into()) {
+        Ok(key) => respond(format, "key", key),
+        Err(e) => reject(format, e),
+    }
+}
+
+fn default_mode(addr: IpAddr) -> Mode {
+    if addr.is_ipv4() {
+        default_mode_v4()
+    } else {
+        default_mode_v6()
+    }
+}
+
+#[derive(Deserialize)]
+struct AnyDestParams {
+    from: IpAddr,
+    key: IpAddr,
+    mode: Option<Mode>,
+}
+
+impl Expected for AnyDestParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" | "key" => Some(IP),
+            "mode" => Some(MODE),
+            _ => None,
+        }
+    }
+}
+
+/// Destination for any pair of addresses. When the families differ, the IPv6
+/// address is unwrapped if it carries an IPv4 one, or else the IPv4 address
+/// is mapped into IPv6. A destination computed from an unwrapped `from` is
+/// wrapped back up the same way.
+#[axum::debug_handler]
+async fn any_dest(format: Format, Query(params): Query<AnyDestParams>) -> impl IntoResponse {
+    let (from, key, conversion) = mixed::unify(("from", params.from), ("key", params.key));
+    let mode = params.mode.unwrap_or(default_mode(from));
+    let dest = match mode.dest(from, key) {
+        Ok(dest) => dest,
+        Err(e) => return reject(format, e),
+    };
+    let dest = match (conversion, params.from, dest) {
+        (
+            Some(Conversion {
+                param: "from",
+                form,
+                family: Family::Ipv4,
+            }),
+            IpAddr::V6(like),
+            IpAddr::V4(dest),
+        ) => IpAddr::V6(form.wrap(dest, like)),
+        _ => dest,
+    };
+    respond_converted(format, "dest", dest, conversion)
+}
+
+#[derive(Deserialize)]
+struct AnyKeyParams {
+    from: IpAddr,
+    to: IpAddr,
+    mode: Option<Mode>,
+}
+
+impl Expected for AnyKeyParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "from" | "to" => Some(IP),
+            "mode" => Some(MODE),
+            _ => None,
+        }
+    }
+}
+
+/// Key for any pair of addresses, converting between families like `any_dest`.
+#[axum::debug_handler]
+async fn any_key(format: Format, Query(params): Query<AnyKeyParams>) -> impl IntoResponse {
+    let (from, to, conversion) = mixed::unify(("from", params.from), ("to", params.to));
+    let mode = params.mode.unwrap_or(default_mode(from));
+    match mode.key(from, to) {
+        Ok(key) => respond_converted(format, "key", key, conversion),
+        Err(e) => reject(format, e),
+    }
+}
+
+/// One pair in a batch: `key` gets the destination, `to` gets the key. The
+/// mode defaults to add for IPv4 and XOR for IPv6, like the single endpoints.
+#[derive(Deserialize)]
+struct BatchItem {
+    from: IpAddr,
+    key: Option<IpAddr>,
+    to: Option<IpAddr>,
+    mode: Option<Mode>,
 }
 
+impl BatchItem {
+    fn apply(self) -> Result<Value, String> {
+        let mode = self.mode.unwrap_or(default_mode(self.from));
+        match (self.key, self.to) {
+            (Some(key), None) => Ok(json!({"dest": mode.dest(self.from, key)?})),
+            (None, Some(to)) => Ok(json!({"key": mode.key(self.from, to)?})),
+            _ => Err("expected exactly one of `key` or `to`".to_string()),
+        }
+    }
+}
+
+/// Transform many address pairs at once. Takes a JSON array, answered once
+/// it has arrived in full, or one JSON object per line, each answered as
+/// soon as its line arrives. Results come back one per line in the same
+/// order, and bad items get an `error` result instead of failing the whole
+/// request.
 #[axum::debug_handler]
-async fn task4(Query(params): Query<KeyParams6>) -> impl IntoResponse {
-    let key = xor_ipv6(params.from, params.to);
-    format!("{key}").into_response()
+async fn batch(req: Request) -> Response {
+    let results = if Media::from_headers(req.headers()) == Some(Media::Json) {
+        let body = match Bytes::from_request(req, &()).await {
+            Ok(body) => body,
+            Err(rejection) => return rejection.into_response(),
+        };
+        let items = match serde_json::from_slice::<Vec<Value>>(&body) {
+            Ok(items) => items,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Expected a JSON array: {e}\n"),
+                )
+                    .into_response()
+            }
+        };
+        let results = items
+            .into_iter()
+            .map(|item| Ok::<_, Infallible>(batch_result(Ok(item))));
+        Body::from_stream(tokio_stream::iter(results))
+    } else {
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(batch_lines(req.into_body(), sender));
+        Body::from_stream(ReceiverStream::new(receiver))
+    };
+    ([(CONTENT_TYPE, "application/x-ndjson")], results).into_response()
+}
+
+/// Answers each line of `body` as soon as it's complete, until the body
+/// ends or the client stops listening.
+async fn batch_lines(body: Body, results: mpsc::Sender<Result<String, Infallible>>) {
+    let mut chunks = body.into_data_stream();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = results.send(Ok(batch_result(Err(e.to_string())))).await;
+                return;
+            }
+        };
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if !batch_line(&line, &results).await {
+                return;
+            }
+        }
+        if buffer.len() > MAX_BATCH_LINE {
+            let error = format!("lines can be at most {MAX_BATCH_LINE} bytes");
+            let _ = results.send(Ok(batch_result(Err(error)))).await;
+            return;
+        }
+    }
+    batch_line(&buffer, &results).await;
+}
+
+/// False once nobody is listening for results.
+async fn batch_line(line: &[u8], results: &mpsc::Sender<Result<String, Infallible>>) -> bool {
+    if line.trim_ascii().is_empty() {
+        return true;
+    }
+    let item = serde_json::from_slice(line).map_err(|e| e.to_string());
+    results.send(Ok(batch_result(item))).await.is_ok()
+}
+
+fn batch_result(item: Result<Value, String>) -> String {
+    let result = item
+        .and_then(|item| serde_json::from_value::<BatchItem>(item).map_err(|e| e.to_string()))
+        .and_then(BatchItem::apply)
+        .unwrap_or_else(|e| json!({"error": e}));
+    format!("{result}\n")
+}
+
+fn add_key(from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+    let dest = AddSub.encode(&from.octets(), &key.octets());
+    <[u8; 4]>::try_from(dest).unwrap().into()
 }
 
-fn xor_ipv6(lhs: Ipv6Addr, rhs: Ipv6Addr) -> Ipv6Addr {
-    let lhs = lhs.octets();
-    let rhs = rhs.octets();
-    let res: Vec<u8> = lhs.iter().zip(rhs).map(|(a, b)| a ^ b).collect();
-    let res: [u8; 16] = res.try_into().unwrap();
-    res.into()
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Every address of `net` with `key` added.
+    fn dests(net: Ipv4Net, key: Ipv4Addr) -> HashSet<Ipv4Addr> {
+        (0..net.size()).map(|n| add_key(net.nth(n), key)).collect()
+    }
+
+    #[test]
+    fn with_key_maps_partial_octet_block() {
+        let net: Ipv4Net = "10.0.16.0/20".parse().unwrap();
+        // the low four bits of the third octet stay clear of the host part
+        let key = Ipv4Addr::new(1, 2, 48, 7);
+        let block = net.with_key(key).expect("key keeps the block together");
+        assert_eq!(block.to_string(), "11.2.64.0/20");
+        let expected: HashSet<_> = (0..block.size()).map(|n| block.nth(n)).collect();
+        assert_eq!(dests(net, key), expected);
+    }
+
+    #[test]
+    fn with_key_rejects_splitting_key() {
+        let net: Ipv4Net = "10.0.16.0/20".parse().unwrap();
+        let key = Ipv4Addr::new(1, 2, 3, 4);
+        assert!(net.with_key(key).is_none());
+        // the addresses really don't form a /20
+        let networks: HashSet<_> = dests(net, key)
+            .into_iter()
+            .map(|addr| u32::from(addr) >> 12)
+            .collect();
+        assert!(networks.len() > 1);
+    }
+
+    #[test]
+    fn with_key_maps_whole_octet_block() {
+        let net: Ipv4Net = "10.0.0.0/24".parse().unwrap();
+        let key = Ipv4Addr::new(1, 2, 3, 4);
+        let block = net.with_key(key).unwrap();
+        assert_eq!(block.to_string(), "11.2.3.0/24");
+        let expected: HashSet<_> = (0..block.size()).map(|n| block.nth(n)).collect();
+        assert_eq!(dests(net, key), expected);
+    }
 }
@@ -0,0 +1,258 @@
+//! Ways of encoding an address with a key. Every mode works on both address
+//! families, and the key can be recovered from an address and its encoding.
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+/// Encodes addresses, given as octets, with a key of the same length.
+pub trait AddressCipher {
+    fn encode(&self, from: &[u8], key: &[u8]) -> Vec<u8>;
+
+    /// The key that encodes `from` as `to`, if there is one.
+    fn key(&self, from: &[u8], to: &[u8]) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Octet-wise wrapping addition.
+    Add,
+    Xor,
+    /// Rotation to the left by the key, as a number of bits.
+    Rotate,
+    /// Keyed permutation over the whole address space of the family.
+    Permute,
+}
+
+impl Mode {
+    fn cipher(self) -> &'static dyn AddressCipher {
+        match self {
+            Self::Add => &AddSub,
+            Self::Xor => &Xor,
+            Self::Rotate => &Rotate,
+            Self::Permute => &Permute,
+        }
+    }
+
+    pub fn dest(self, from: IpAddr, key: IpAddr) -> Result<IpAddr, String> {
+        let (from, key) = same_family(from, key)?;
+        Ok(to_addr(&self.cipher().encode(&from, &key)))
+    }
+
+    pub fn key(self, from: IpAddr, to: IpAddr) -> Result<IpAddr, String> {
+        let (from, to) = same_family(from, to)?;
+        let key = self
+            .cipher()
+            .key(&from, &to)
+            .ok_or("no key encodes `from` as `to` in this mode")?;
+        Ok(to_addr(&key))
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn same_family(lhs: IpAddr, rhs: IpAddr) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if lhs.is_ipv4() != rhs.is_ipv4() {
+        return Err("addresses must be the same family".to_string());
+    }
+    Ok((octets(lhs), octets(rhs)))
+}
+
+fn to_addr(octets: &[u8]) -> IpAddr {
+    match octets.len() {
+        4 => <[u8; 4]>::try_from(octets).unwrap().into(),
+        _ => <[u8; 16]>::try_from(octets).unwrap().into(),
+    }
+}
+
+/// Address as a number, along with its width in bits.
+fn to_int(octets: &[u8]) -> (u128, u32) {
+    let value = octets.iter().fold(0, |acc, &b| acc << 8 | b as u128);
+    (value, octets.len() as u32 * 8)
+}
+
+fn from_int(value: u128, bits: u32) -> Vec<u8> {
+    value.to_be_bytes()[(128 - bits as usize) / 8..].to_vec()
+}
+
+fn mask(bits: u32) -> u128 {
+    u128::MAX >> (128 - bits)
+}
+
+pub struct AddSub;
+
+impl AddressCipher for AddSub {
+    fn encode(&self, from: &[u8], key: &[u8]) -> Vec<u8> {
+        from.iter()
+            .zip(key)
+            .map(|(a, b)| a.wrapping_add(*b))
+            .collect()
+    }
+
+    fn key(&self, from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+        Some(
+            to.iter()
+                .zip(from)
+                .map(|(a, b)| a.wrapping_sub(*b))
+                .collect(),
+        )
+    }
+}
+
+pub struct Xor;
+
+impl AddressCipher for Xor {
+    fn encode(&self, from: &[u8], key: &[u8]) -> Vec<u8> {
+        from.iter().zip(key).map(|(a, b)| a ^ b).collect()
+    }
+
+    fn key(&self, from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+        Some(self.encode(from, to))
+    }
+}
+
+pub struct Rotate;
+
+impl Rotate {
+    fn rotate(value: u128, bits: u32, by: u32) -> u128 {
+        let by = by % bits;
+        if by == 0 {
+            return value;
+        }
+        (value << by | value >> (bits - by)) & mask(bits)
+    }
+}
+
+impl AddressCipher for Rotate {
+    fn encode(&self, from: &[u8], key: &[u8]) -> Vec<u8> {
+        let (value, bits) = to_int(from);
+        let (by, _) = to_int(key);
+        let by = (by % bits as u128) as u32;
+        from_int(Self::rotate(value, bits, by), bits)
+    }
+
+    /// The smallest rotation that works, since several might.
+    fn key(&self, from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+        let (from, bits) = to_int(from);
+        let (to, _) = to_int(to);
+        let by = (0..bits).find(|&by| Self::rotate(from, bits, by) == to)?;
+        Some(from_int(by as u128, bits))
+    }
+}
+
+/// XORs the key into the address, then shuffles the result with a fixed
+/// Feistel network. Since the shuffling isn't keyed it can be undone, which
+/// is what makes the key recoverable.
+pub struct Permute;
+
+const FEISTEL_ROUNDS: [u64; 4] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xbf58_476d_1ce4_e5b9,
+    0x94d0_49bb_1331_11eb,
+    0x2545_f491_4f6c_dd1d,
+];
+
+impl Permute {
+    /// Round function, mixing one half of the address (splitmix64).
+    fn round(half: u128, constant: u64, half_bits: u32) -> u128 {
+        let mut z = (half as u64).wrapping_add(constant);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        z as u128 & mask(half_bits)
+    }
+
+    fn shuffle(value: u128, bits: u32) -> u128 {
+        let half_bits = bits / 2;
+        let (mut left, mut right) = (value >> half_bits, value & mask(half_bits));
+        for constant in FEISTEL_ROUNDS {
+            (left, right) = (right, left ^ Self::round(right, constant, half_bits));
+        }
+        left << half_bits | right
+    }
+
+    fn unshuffle(value: u128, bits: u32) -> u128 {
+        let half_bits = bits / 2;
+        let (mut left, mut right) = (value >> half_bits, value & mask(half_bits));
+        for constant in FEISTEL_ROUNDS.into_iter().rev() {
+            (left, right) = (right ^ Self::round(left, constant, half_bits), left);
+        }
+        left << half_bits | right
+    }
+}
+
+impl AddressCipher for Permute {
+    fn encode(&self, from: &[u8], key: &[u8]) -> Vec<u8> {
+        let (value, bits) = to_int(from);
+        let (key, _) = to_int(key);
+        from_int(Self::shuffle(value ^ key, bits), bits)
+    }
+
+    fn key(&self, from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+        let (from, bits) = to_int(from);
+        let (to, _) = to_int(to);
+        Some(from_int(Self::unshuffle(to, bits) ^ from, bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [Mode; 4] = [Mode::Add, Mode::Xor, Mode::Rotate, Mode::Permute];
+
+    fn pairs() -> Vec<(IpAddr, IpAddr)> {
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let rotated = |addr: IpAddr, by| {
+            let (value, bits) = to_int(&octets(addr));
+            to_addr(&from_int(Rotate::rotate(value, bits, by), bits))
+        };
+        vec![
+            (v4, "192.168.255.7".parse().unwrap()),
+            (v4, rotated(v4, 13)),
+            (v6, "fe80::dead:beef".parse().unwrap()),
+            (v6, rotated(v6, 77)),
+        ]
+    }
+
+    #[test]
+    fn key_inverts_dest() {
+        for mode in MODES {
+            for (from, to) in pairs() {
+                // only rotations of `from` can be reached by rotating it
+                let Ok(key) = mode.key(from, to) else {
+                    assert!(
+                        matches!(mode, Mode::Rotate),
+                        "{mode:?}: no key for {from} -> {to}"
+                    );
+                    continue;
+                };
+                assert_eq!(mode.dest(from, key), Ok(to), "{mode:?}: {from} -> {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn rotate_finds_rotations() {
+        for (from, to) in pairs().into_iter().skip(1).step_by(2) {
+            assert!(Mode::Rotate.key(from, to).is_ok(), "{from} -> {to}");
+        }
+    }
+
+    #[test]
+    fn families_must_match() {
+        for mode in MODES {
+            let v4 = "10.0.0.1".parse().unwrap();
+            let v6 = "::1".parse().unwrap();
+            assert!(mode.dest(v4, v6).is_err());
+            assert!(mode.key(v6, v4).is_err());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Bringing an IPv4 and an IPv6 address to a common family.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use serde::Serialize;
+
+/// Ways an IPv6 address can carry an IPv4 one.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Form {
+    /// `::ffff:a.b.c.d`
+    Ipv4Mapped,
+    /// `::a.b.c.d`
+    Ipv4Compatible,
+    /// `2002:aabb:ccdd::/48`
+    #[serde(rename = "6to4")]
+    SixToFour,
+}
+
+impl Form {
+    /// The IPv4 address embedded in `addr`, and how it's embedded.
+    pub fn unwrap(addr: Ipv6Addr) -> Option<(Ipv4Addr, Self)> {
+        if let Some(v4) = addr.to_ipv4_mapped() {
+            return Some((v4, Self::Ipv4Mapped));
+        }
+        let segments = addr.segments();
+        // `::` and `::1` are special addresses rather than compatible ones
+        if segments[..6] == [0; 6] && u32::from(addr.to_ipv4()?) > 1 {
+            return Some((addr.to_ipv4()?, Self::Ipv4Compatible));
+        }
+        if segments[0] == 0x2002 {
+            let v4 = (segments[1] as u32) << 16 | segments[2] as u32;
+            return Some((v4.into(), Self::SixToFour));
+        }
+        None
+    }
+
+    /// `like` with its embedded IPv4 address replaced by `addr`.
+    pub fn wrap(self, addr: Ipv4Addr, like: Ipv6Addr) -> Ipv6Addr {
+        match self {
+            Self::Ipv4Mapped => addr.to_ipv6_mapped(),
+            #[allow(deprecated)] // nothing else builds a compatible address
+            Self::Ipv4Compatible => addr.to_ipv6_compatible(),
+            Self::SixToFour => {
+                let mut segments = like.segments();
+                let addr = u32::from(addr);
+                segments[1] = (addr >> 16) as u16;
+                segments[2] = addr as u16;
+                segments.into()
+            }
+        }
+    }
+}
+
+impl Display for Form {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ipv4Mapped => "IPv4-mapped",
+            Self::Ipv4Compatible => "IPv4-compatible",
+            Self::SixToFour => "6to4",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+/// How one address of a pair was changed to match the other's family.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Conversion {
+    /// Query parameter holding the converted address.
+    pub param: &'static str,
+    pub form: Form,
+    /// Family the address was converted to.
+    pub family: Family,
+}
+
+impl Display for Conversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.family {
+            Family::Ipv4 => write!(f, "{} unwrapped from {} IPv6", self.param, self.form),
+            Family::Ipv6 => write!(f, "{} mapped into IPv6 as {}", self.param, self.form),
+        }
+    }
+}
+
+/// Bring two addresses, named after their query parameters, to the same
+/// family. An IPv6 address carrying an IPv4 one gets unwrapped, otherwise the
+/// IPv4 address is mapped into IPv6.
+pub fn unify(
+    lhs: (&'static str, IpAddr),
+    rhs: (&'static str, IpAddr),
+) -> (IpAddr, IpAddr, Option<Conversion>) {
+    let ((v4_param, v4), (v6_param, v6)) = match (lhs, rhs) {
+        ((v4_param, IpAddr::V4(v4)), (v6_param, IpAddr::V6(v6)))
+        | ((v6_param, IpAddr::V6(v6)), (v4_param, IpAddr::V4(v4))) => {
+            ((v4_param, v4), (v6_param, v6))
+        }
+        _ => return (lhs.1, rhs.1, None),
+    };
+    let (v4, v6, conversion) = match Form::unwrap(v6) {
+        Some((unwrapped, form)) => (
+            IpAddr::V4(v4),
+            IpAddr::V4(unwrapped),
+            Conversion {
+                param: v6_param,
+                form,
+                family: Family::Ipv4,
+            },
+        ),
+        None => (
+            IpAddr::V6(v4.to_ipv6_mapped()),
+            IpAddr::V6(v6),
+            Conversion {
+                param: v4_param,
+                form: Form::Ipv4Mapped,
+                family: Family::Ipv6,
+            },
+        ),
+    };
+    if lhs.1.is_ipv4() {
+        (v4, v6, Some(conversion))
+    } else {
+        (v6, v4, Some(conversion))
+    }
+}
@@ -1,22 +1,28 @@
-use std::fmt::Write;
+use std::{fmt::Write, sync::Arc};
 
 use axum::{
-    extract::Path,
+    extract::{Path, State},
     http::StatusCode,
     response::{IntoResponse, Result},
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 use axum_extra::extract::Multipart;
 use html_escape::encode_safe;
 use serde::Deserialize;
+use serde_json::json;
 
-pub fn router() -> Router {
+use crate::advisory::{self, Database};
+use crate::extract::ValidPayload;
+
+pub fn router(advisories: Arc<Database>) -> Router {
     Router::new()
         .route("/23/star", get(star))
         .route("/23/present/:color", get(present))
         .route("/23/ornament/:state/:n", get(ornament))
         .route("/23/lockfile", post(lockfile))
+        .route("/23/audit", post(audit))
+        .with_state(advisories)
 }
 
 #[axum::debug_handler]
@@ -65,6 +71,8 @@ struct Lockfile {
 
 #[derive(Debug, Deserialize)]
 struct Package {
+    name: Option<String>,
+    version: Option<String>,
     checksum: Option<String>,
 }
 
@@ -123,3 +131,24 @@ async fn lockfile(mut multipart: Multipart) -> Result<String, StatusCode> {
     // dbg!(&res);
     Ok(res)
 }
+
+/// Check the packages of a lockfile, sent as TOML, against the advisory
+/// database.
+#[axum::debug_handler]
+async fn audit(
+    State(advisories): State<Arc<Database>>,
+    ValidPayload(lockfile): ValidPayload<Lockfile>,
+) -> impl IntoResponse {
+    let mut report = advisory::Report::default();
+    for package in lockfile.package {
+        let (Some(name), Some(version)) = (package.name, package.version) else {
+            continue;
+        };
+        let Ok(version) = semver::Version::parse(&version) else {
+            eprintln!("Can't audit {name} {version}");
+            continue;
+        };
+        report.check_version(&advisories, name, version);
+    }
+    Json(json!(report))
+}
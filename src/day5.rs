@@ -1,115 +1,455 @@
+use std::{fmt::Write, sync::Arc};
+
 use axum::{
     async_trait,
     body::Bytes,
-    extract::{FromRequest, Request},
-    http::{header::CONTENT_TYPE, StatusCode},
-    response::IntoResponse,
+    extract::{FromRequest, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::post,
-    Router,
+    Json, Router,
+};
+use cargo_manifest::{
+    Dependency, DepsSet, Manifest, MaybeInherited, Package, Publish, StringOrBool, WorkspacePackage,
 };
-use cargo_manifest::Manifest;
-use std::fmt::Write;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::advisory::{self, Database};
+use crate::extract::{Expected, Format, Media, PayloadRejection, Query};
+use convert::{Document, Target};
+
+mod convert;
+mod lint;
+mod orders;
 
-pub fn router() -> Router {
-    Router::new().route("/5/manifest", post(task1))
+pub fn router(advisories: Arc<Database>) -> Router {
+    Router::new()
+        .route("/5/manifest", post(task1))
+        .route("/5/manifest/totals", post(totals))
+        .route("/5/inspect", post(inspect))
+        .route("/5/lint", post(lint))
+        .route("/5/convert", post(convert))
+        .route("/5/audit", post(audit))
+        .with_state(advisories)
 }
 
+#[derive(Deserialize)]
+struct OrderParams {
+    /// Reject the manifest if any order is invalid, rather than skipping it.
+    #[serde(default)]
+    strict: bool,
+}
+
+impl Expected for OrderParams {
+    fn expected(_field: &str) -> Option<&'static str> {
+        Some("true or false")
+    }
+}
+
+/// List the valid orders. As JSON, invalid orders are reported alongside.
 #[axum::debug_handler]
-async fn task1(ValidPayload(manifest): ValidPayload<Manifest>) -> Result<String, Error> {
+async fn task1(
+    format: Format,
+    Query(params): Query<OrderParams>,
+    ValidManifest(manifest): ValidManifest,
+) -> Result<Response, Error> {
+    let (orders, invalid) = manifest_orders(manifest, params.strict)?;
+    match format {
+        Format::Text => {
+            let mut output = String::new();
+            for order in orders {
+                writeln!(output, "{}: {}", order.item, order.quantity).unwrap();
+            }
+            Ok(output.trim().to_string().into_response())
+        }
+        Format::Json => Ok(Json(json!({"orders": orders, "invalid": invalid})).into_response()),
+    }
+}
+
+/// Orders grouped by item and unit, with quantities summed and totals for
+/// priced items. Plain text, JSON or CSV, depending on the `Accept` header.
+#[axum::debug_handler]
+async fn totals(
+    headers: HeaderMap,
+    Query(params): Query<OrderParams>,
+    ValidManifest(manifest): ValidManifest,
+) -> Result<Response, Error> {
+    let (orders, _) = manifest_orders(manifest, params.strict)?;
+    let totals = orders::aggregate(&orders);
+    Ok(orders::Report::from_headers(&headers).render(&totals))
+}
+
+/// The valid orders of a manifest with the magic keyword, and reports on the
+/// invalid ones. In `strict` mode, any invalid order rejects the manifest.
+fn manifest_orders(
+    manifest: Manifest,
+    strict: bool,
+) -> Result<(Vec<orders::Order>, Vec<orders::Invalid>), Error> {
     let package = manifest.package.ok_or(Error::NoContent)?;
+    // keywords inherited from a workspace can't be seen here
     let keywords = package
         .keywords
-        .ok_or(Error::NoKeyword)?
-        .as_local()
-        .unwrap();
+        .and_then(|keywords| keywords.as_local())
+        .ok_or(Error::NoKeyword)?;
     if !keywords.contains(&"Christmas 2024".to_string()) {
         return Err(Error::NoKeyword);
     }
     let metadata = package.metadata.ok_or(Error::NoContent)?;
-    let orders = metadata.get("orders").ok_or(Error::NoContent)?;
-    let mut output = String::new();
-    for order in orders.as_array().unwrap() {
-        let order = order.as_table().unwrap();
-        let Some(item) = order.get("item") else {
-            continue;
+    let orders = metadata
+        .get("orders")
+        .and_then(|orders| orders.as_array())
+        .ok_or(Error::NoContent)?;
+    let (orders, invalid) = orders::parse_all(orders);
+    if strict && !invalid.is_empty() {
+        return Err(Error::InvalidOrders(invalid));
+    }
+    if orders.is_empty() {
+        return Err(Error::NoContent);
+    }
+    Ok((orders, invalid))
+}
+
+#[derive(Serialize)]
+struct Inspection {
+    package: Option<PackageSummary>,
+    workspace: Option<WorkspaceSummary>,
+    dependencies: Vec<DependencySummary>,
+}
+
+/// Package fields, with those set to `workspace = true` taken from the
+/// manifest's own `workspace.package`. Inherited fields that can't be
+/// resolved from the submitted manifest are listed in `unresolved`.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PackageSummary {
+    name: String,
+    version: Option<String>,
+    edition: Option<cargo_manifest::Edition>,
+    rust_version: Option<String>,
+    authors: Option<Vec<String>>,
+    description: Option<String>,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    readme: Option<StringOrBool>,
+    keywords: Option<Vec<String>>,
+    categories: Option<Vec<String>>,
+    license: Option<String>,
+    license_file: Option<String>,
+    repository: Option<String>,
+    publish: Option<Publish>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    unresolved: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct WorkspaceSummary {
+    members: Vec<String>,
+    exclude: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DependencySummary {
+    name: String,
+    /// `normal`, `dev`, `build`, or `workspace` for `workspace.dependencies`.
+    kind: &'static str,
+    /// The `cfg(...)` or triple of a `target.*.dependencies` table.
+    target: Option<String>,
+    /// Version requirement, if any. `None` for inherited dependencies missing
+    /// from `workspace.dependencies`.
+    req: Option<String>,
+    features: Vec<String>,
+    optional: bool,
+    /// Whether the dependency is inherited with `workspace = true`.
+    inherited: bool,
+    /// Whether the dependency comes from a registry, not a path or git.
+    registry: bool,
+}
+
+/// Summarise a package or workspace root manifest: workspace members, the
+/// package with its inherited fields resolved, and every dependency table.
+#[axum::debug_handler]
+async fn inspect(ValidManifest(manifest): ValidManifest) -> Result<impl IntoResponse, Error> {
+    if manifest.package.is_none() && manifest.workspace.is_none() {
+        return Err(Error::NoContent);
+    }
+    let dependencies = dependencies(&manifest);
+    let workspace_package = manifest.workspace.as_ref().and_then(|w| w.package.as_ref());
+
+    let package = manifest.package.map(|package| {
+        let mut inherit = Inheritance {
+            workspace: workspace_package,
+            unresolved: Vec::new(),
         };
-        let Some(quantity) = order.get("quantity") else {
+        PackageSummary {
+            name: package.name,
+            version: inherit.resolve("version", package.version, |w| w.version.clone()),
+            edition: inherit.resolve("edition", package.edition, |w| w.edition),
+            rust_version: inherit.resolve("rust-version", package.rust_version, |w| {
+                w.rust_version.clone()
+            }),
+            authors: inherit.resolve("authors", package.authors, |w| w.authors.clone()),
+            description: inherit.resolve("description", package.description, |w| {
+                w.description.clone()
+            }),
+            homepage: inherit.resolve("homepage", package.homepage, |w| w.homepage.clone()),
+            documentation: inherit.resolve("documentation", package.documentation, |w| {
+                w.documentation.clone()
+            }),
+            readme: inherit.resolve("readme", package.readme, |w| w.readme.clone()),
+            keywords: inherit.resolve("keywords", package.keywords, |w| w.keywords.clone()),
+            categories: inherit.resolve("categories", package.categories, |w| w.categories.clone()),
+            license: inherit.resolve("license", package.license, |w| w.license.clone()),
+            license_file: inherit.resolve("license-file", package.license_file, |w| {
+                w.license_file.clone()
+            }),
+            repository: inherit.resolve("repository", package.repository, |w| w.repository.clone()),
+            publish: inherit.resolve("publish", package.publish, |w| w.publish.clone()),
+            include: inherit.resolve("include", package.include, |w| w.include.clone()),
+            exclude: inherit.resolve("exclude", package.exclude, |w| w.exclude.clone()),
+            unresolved: inherit.unresolved,
+        }
+    });
+
+    let workspace = manifest
+        .workspace
+        .as_ref()
+        .map(|workspace| WorkspaceSummary {
+            members: workspace.members.clone(),
+            exclude: workspace.exclude.clone().unwrap_or_default(),
+        });
+
+    Ok(Json(Inspection {
+        package,
+        workspace,
+        dependencies,
+    }))
+}
+
+/// Every dependency table of the manifest, including the workspace's.
+fn dependencies(manifest: &Manifest) -> Vec<DependencySummary> {
+    let workspace_deps = manifest
+        .workspace
+        .as_ref()
+        .and_then(|w| w.dependencies.as_ref());
+    let mut dependencies = Vec::new();
+    let mut add = |deps: Option<&DepsSet>, kind, target: Option<&String>| {
+        for (name, dep) in deps.into_iter().flatten() {
+            dependencies.push(summarise(name, dep, kind, target, workspace_deps));
+        }
+    };
+    add(manifest.dependencies.as_ref(), "normal", None);
+    add(manifest.dev_dependencies.as_ref(), "dev", None);
+    add(manifest.build_dependencies.as_ref(), "build", None);
+    for (target, deps) in manifest.target.iter().flatten() {
+        add(Some(&deps.dependencies), "normal", Some(target));
+        add(Some(&deps.dev_dependencies), "dev", Some(target));
+        add(Some(&deps.build_dependencies), "build", Some(target));
+    }
+    add(workspace_deps, "workspace", None);
+    dependencies
+}
+
+/// Check registry dependencies against the advisory database. Each is
+/// checked at the lowest version its requirement allows.
+#[axum::debug_handler]
+async fn audit(
+    State(advisories): State<Arc<Database>>,
+    ValidManifest(manifest): ValidManifest,
+) -> impl IntoResponse {
+    let mut checked: Vec<(String, String)> = Vec::new();
+    let mut report = advisory::Report::default();
+    for dependency in dependencies(&manifest) {
+        let Some(req) = dependency.req.filter(|_| dependency.registry) else {
             continue;
         };
-        let Some(quantity) = quantity.as_integer() else {
+        if checked.contains(&(dependency.name.clone(), req.clone())) {
+            continue;
+        }
+        checked.push((dependency.name.clone(), req.clone()));
+        let Ok(parsed) = semver::VersionReq::parse(&req) else {
+            eprintln!("Can't audit {} {req}", dependency.name);
             continue;
         };
-        writeln!(output, "{}: {}", item.as_str().unwrap(), quantity).unwrap();
+        report.check_req(&advisories, dependency.name, &parsed);
     }
+    Json(json!(report))
+}
 
-    if output.is_empty() {
-        return Err(Error::NoContent);
+impl Expected for lint::Config {
+    fn expected(_field: &str) -> Option<&'static str> {
+        Some("comma separated rule names, like missing-license,wildcard-dependency")
     }
-    Ok(output.trim().to_string())
+}
+
+/// Check a manifest against the lint rules. Rules can be switched on and off
+/// with the `enable` and `disable` query parameters, and made errors with
+/// `deny`.
+#[axum::debug_handler]
+async fn lint(
+    Query(config): Query<lint::Config>,
+    ValidManifest(manifest): ValidManifest,
+) -> impl IntoResponse {
+    let findings = lint::check(&manifest, &config);
+    Json(json!({ "findings": findings }))
+}
+
+/// Write a manifest out again in the format named by the `Accept` header,
+/// or the one it was submitted in.
+/// Keys stay in the order they were submitted in.
+#[axum::debug_handler]
+async fn convert(headers: HeaderMap, document: Document) -> Result<impl IntoResponse, Error> {
+    let target = Target::negotiate(&headers, document.format()).ok_or(Error::NotAcceptable)?;
+    let output = document.render(target).map_err(Error::Unrepresentable)?;
+    Ok(([(CONTENT_TYPE, target.content_type())], output))
+}
+
+/// Resolves package fields set to `workspace = true` from the manifest's
+/// own `workspace.package`, noting those it has no value for.
+struct Inheritance<'a> {
+    workspace: Option<&'a WorkspacePackage>,
+    unresolved: Vec<&'static str>,
+}
+
+impl Inheritance<'_> {
+    fn resolve<T>(
+        &mut self,
+        name: &'static str,
+        field: Option<MaybeInherited<T>>,
+        inherited: impl FnOnce(&WorkspacePackage) -> Option<T>,
+    ) -> Option<T> {
+        match field? {
+            MaybeInherited::Local(value) => Some(value),
+            MaybeInherited::Inherited { .. } => {
+                let value = self.workspace.and_then(inherited);
+                if value.is_none() {
+                    self.unresolved.push(name);
+                }
+                value
+            }
+        }
+    }
+}
+
+fn summarise(
+    name: &str,
+    dep: &Dependency,
+    kind: &'static str,
+    target: Option<&String>,
+    workspace_deps: Option<&DepsSet>,
+) -> DependencySummary {
+    let inherited = matches!(dep, Dependency::Inherited(_));
+    let source = if inherited {
+        workspace_deps.and_then(|deps| deps.get(name))
+    } else {
+        Some(dep)
+    };
+    // inherited dependencies add their features to the workspace's
+    let mut features: Vec<String> = source
+        .filter(|_| inherited)
+        .map(|source| source.req_features().to_vec())
+        .unwrap_or_default();
+    features.extend(dep.req_features().iter().cloned());
+    features.sort();
+    features.dedup();
+    DependencySummary {
+        name: name.to_string(),
+        kind,
+        target: target.cloned(),
+        req: source.map(|source| source.req().to_string()),
+        features,
+        optional: dep.optional(),
+        inherited,
+        registry: source.is_some_and(|source| match source {
+            Dependency::Detailed(detail) => detail.path.is_none() && detail.git.is_none(),
+            _ => true,
+        }),
+    }
+}
+
+/// A manifest decoded according to its `Content-Type`.
+pub struct ValidManifest(pub Manifest);
+
+#[async_trait]
+impl<S> FromRequest<S> for ValidManifest
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(media) = Media::from_headers(req.headers()) else {
+            eprintln!("Unsupported media {:?}", req.headers().get(CONTENT_TYPE));
+            return Err(PayloadRejection::UnsupportedMedia.into());
+        };
+        let payload = Bytes::from_request(req, state)
+            .await
+            .map_err(PayloadRejection::from)?;
+        Ok(Self(decode_manifest(media, &payload)?))
+    }
+}
+
+/// Like `Manifest::from_slice`: some old manifests lack the `[package]`
+/// header, and have its keys under `[project]` or at the top level.
+fn decode_manifest(media: Media, body: &[u8]) -> Result<Manifest, PayloadRejection> {
+    #[derive(Deserialize)]
+    struct Project {
+        project: Option<Package>,
+    }
+
+    let mut manifest: Manifest = media.decode(body)?;
+    if manifest.package.is_none() && manifest.workspace.is_none() {
+        let package = match media.decode::<Project>(body)?.project {
+            Some(project) => project,
+            None => media.decode(body)?,
+        };
+        manifest.package = Some(package);
+    }
+    Ok(manifest)
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Failed to deserialize the request body")]
-    Manifest(#[from] cargo_manifest::Error),
-    #[error("Failed to deserialize the request body")]
-    SerdeYaml(#[from] serde_yaml::Error),
-    #[error("Request body didn't contain valid bytes")]
-    StringRejection(#[from] axum::extract::rejection::BytesRejection),
-    #[error("Media type not supported")]
-    UnsupportedMedia,
+    #[error(transparent)]
+    Payload(#[from] PayloadRejection),
+    #[error("None of the accepted media types can be produced")]
+    NotAcceptable,
+    #[error("Manifest can't be written in the requested format: {0}")]
+    Unrepresentable(String),
     #[error("No orders in request")]
     NoContent,
     #[error("Magic keyword not provided")]
     NoKeyword,
+    #[error("Manifest contains invalid orders")]
+    InvalidOrders(Vec<orders::Invalid>),
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         match self {
-            Self::Manifest(e) => {
+            Self::Payload(e @ PayloadRejection::Invalid { .. }) => {
+                eprintln!("ManifestError: {e:?}");
+                e.respond_as("Invalid manifest")
+            }
+            Self::Payload(e @ PayloadRejection::Utf8(_)) => {
                 eprintln!("ManifestError: {e}");
                 (StatusCode::BAD_REQUEST, "Invalid manifest").into_response()
             }
-            Self::SerdeYaml(e) => {
-                eprintln!("SerdeYamlError: {e}");
-                (StatusCode::BAD_REQUEST, "Invalid manifest").into_response()
+            Self::Payload(rejection) => rejection.into_response(),
+            Self::NotAcceptable => (StatusCode::NOT_ACCEPTABLE).into_response(),
+            Self::Unrepresentable(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
             }
-            Self::StringRejection(error) => error.into_response(),
-            Self::UnsupportedMedia => (StatusCode::UNSUPPORTED_MEDIA_TYPE).into_response(),
             Self::NoContent => (StatusCode::NO_CONTENT).into_response(),
             Self::NoKeyword => {
                 (StatusCode::BAD_REQUEST, "Magic keyword not provided").into_response()
             }
+            Self::InvalidOrders(invalid) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({"error": "Invalid orders", "orders": invalid})),
+            )
+                .into_response(),
         }
     }
 }
-
-pub struct ValidPayload<T>(pub T);
-
-#[async_trait]
-impl<S> FromRequest<S> for ValidPayload<Manifest>
-where
-    S: Send + Sync,
-{
-    type Rejection = Error;
-
-    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
-        let content_type_header = req.headers().get(CONTENT_TYPE);
-        let content_type = content_type_header.and_then(|v| v.to_str().ok());
-        if let Some(content_type) = content_type {
-            if content_type.starts_with("application/toml") {
-                let payload = Bytes::from_request(req, state).await?;
-                return Ok(Self(cargo_manifest::Manifest::from_slice(&payload)?));
-            }
-            if content_type.starts_with("application/yaml")
-                || content_type.starts_with("application/json")
-            {
-                let payload = Bytes::from_request(req, state).await?;
-                return Ok(Self(serde_yaml::from_slice(&payload)?));
-            }
-        }
-        eprintln!("Unsupported media {:?}", content_type);
-        Err(Self::Rejection::UnsupportedMedia)
-    }
-}
@@ -0,0 +1,211 @@
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::HeaderMap,
+};
+use serde_yaml::{Mapping, Value};
+use toml_edit::{ArrayOfTables, DocumentMut, InlineTable, Item, Table};
+
+use super::{decode_manifest, Error};
+use crate::extract::{negotiate, Media, PayloadRejection, Representation};
+
+/// A manifest as it was submitted, before it's read into a `Manifest`, so
+/// nothing is dropped when it's written out again. Both forms keep their
+/// keys in document order.
+pub enum Document {
+    Toml {
+        source: String,
+        table: toml::Table,
+    },
+    /// YAML, or JSON parsed as YAML.
+    Yaml {
+        value: Value,
+        json: bool,
+    },
+}
+
+/// The manifest as submitted, after checking that it is one.
+#[async_trait]
+impl<S> FromRequest<S> for Document
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let media = Media::from_headers(req.headers());
+        let payload = Bytes::from_request(req, state)
+            .await
+            .map_err(PayloadRejection::from)?;
+        match media {
+            Some(Media::Toml) => {
+                let source = std::str::from_utf8(&payload)
+                    .map_err(PayloadRejection::from)?
+                    .to_string();
+                let table = Media::Toml.decode(&payload)?;
+                decode_manifest(Media::Toml, &payload)?;
+                Ok(Self::Toml { source, table })
+            }
+            Some(media @ (Media::Yaml | Media::Json)) => {
+                // JSON is read as YAML too, which keeps its keys in order
+                let value = Media::Yaml.decode(&payload)?;
+                decode_manifest(media, &payload)?;
+                Ok(Self::Yaml {
+                    value,
+                    json: media == Media::Json,
+                })
+            }
+            _ => Err(PayloadRejection::UnsupportedMedia.into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Target {
+    const ALL: [Self; 3] = [Self::Toml, Self::Yaml, Self::Json];
+
+    /// The format the `Accept` header prefers, `input` when any format will
+    /// do.
+    pub fn negotiate(headers: &HeaderMap, input: Self) -> Option<Self> {
+        let offered = Self::ALL.map(Self::representation);
+        let chosen = negotiate(headers, &offered, input.representation())?;
+        Self::ALL
+            .into_iter()
+            .find(|target| target.representation() == chosen)
+    }
+
+    fn representation(self) -> Representation {
+        match self {
+            Self::Toml => Representation::Toml,
+            Self::Yaml => Representation::Yaml,
+            Self::Json => Representation::Json,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        self.representation().content_type()
+    }
+}
+
+impl Document {
+    /// The format the document was submitted in.
+    pub fn format(&self) -> Target {
+        match self {
+            Self::Toml { .. } => Target::Toml,
+            Self::Yaml { json: true, .. } => Target::Json,
+            Self::Yaml { json: false, .. } => Target::Yaml,
+        }
+    }
+
+    /// Fails when the document uses something the target format can't hold,
+    /// like `null` in TOML.
+    pub fn render(&self, target: Target) -> Result<String, String> {
+        match (self, target) {
+            // already as it should be, comments and all
+            (Self::Toml { source, .. }, Target::Toml) => Ok(source.clone()),
+            (Self::Yaml { value, .. }, Target::Toml) => to_toml(value),
+            (_, Target::Yaml) => serde_yaml::to_string(&self.to_yaml()).map_err(|e| e.to_string()),
+            (_, Target::Json) => {
+                serde_json::to_string_pretty(&self.to_yaml()).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn to_yaml(&self) -> Value {
+        match self {
+            Self::Toml { table, .. } => toml_to_yaml(&toml::Value::Table(table.clone())),
+            Self::Yaml { value, .. } => value.clone(),
+        }
+    }
+}
+
+/// Datetimes have no YAML or JSON counterpart, so they're kept as the
+/// string TOML would write them as.
+fn toml_to_yaml(value: &toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s.clone()),
+        toml::Value::Integer(i) => Value::from(*i),
+        toml::Value::Float(f) => Value::from(*f),
+        toml::Value::Boolean(b) => Value::Bool(*b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Sequence(array.iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(table) => Value::Mapping(
+            table
+                .iter()
+                .map(|(key, value)| (Value::String(key.clone()), toml_to_yaml(value)))
+                .collect::<Mapping>(),
+        ),
+    }
+}
+
+/// Top-level tables are written as `[table]` sections and anything deeper
+/// inline, the way manifests are usually laid out. Keeping deeper tables
+/// inline also keeps every key in its original place.
+fn to_toml(value: &Value) -> Result<String, String> {
+    let Value::Mapping(root) = value else {
+        return Err("a manifest must be a table".to_string());
+    };
+    let mut document = DocumentMut::new();
+    for (key, value) in root {
+        let item = match value {
+            Value::Mapping(mapping) => Item::Table(to_table(mapping)?),
+            Value::Sequence(items) if !items.is_empty() && items.iter().all(Value::is_mapping) => {
+                let mut tables = ArrayOfTables::new();
+                for item in items {
+                    tables.push(to_table(item.as_mapping().unwrap())?);
+                }
+                Item::ArrayOfTables(tables)
+            }
+            value => Item::Value(to_value(value)?),
+        };
+        document.insert(to_key(key)?, item);
+    }
+    Ok(document.to_string())
+}
+
+fn to_table(mapping: &Mapping) -> Result<Table, String> {
+    let mut table = Table::new();
+    for (key, value) in mapping {
+        table.insert(to_key(key)?, Item::Value(to_value(value)?));
+    }
+    Ok(table)
+}
+
+fn to_value(value: &Value) -> Result<toml_edit::Value, String> {
+    Ok(match value {
+        Value::Null => return Err("TOML has no null".to_string()),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => i.into(),
+            (None, Some(_)) if n.is_u64() => return Err(format!("{n} is too large for TOML")),
+            (None, Some(f)) => f.into(),
+            (None, None) => return Err(format!("{n} can't be written in TOML")),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Sequence(items) => items
+            .iter()
+            .map(to_value)
+            .collect::<Result<toml_edit::Array, _>>()?
+            .into(),
+        Value::Mapping(mapping) => {
+            let mut table = InlineTable::new();
+            for (key, value) in mapping {
+                table.insert(to_key(key)?, to_value(value)?);
+            }
+            table.into()
+        }
+        Value::Tagged(tagged) => return Err(format!("TOML has no tags like {}", tagged.tag)),
+    })
+}
+
+fn to_key(key: &Value) -> Result<&str, String> {
+    key.as_str()
+        .ok_or_else(|| format!("TOML keys must be strings, not {key:?}"))
+}
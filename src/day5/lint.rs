@@ -0,0 +1,221 @@
+use std::str::FromStr;
+
+use cargo_manifest::{Dependency, DepsSet, Manifest};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    MissingLicense,
+    MissingRustVersion,
+    MissingEdition,
+    MissingDescription,
+    WildcardDependency,
+}
+
+impl Rule {
+    pub const ALL: [Self; 5] = [
+        Self::MissingLicense,
+        Self::MissingRustVersion,
+        Self::MissingEdition,
+        Self::MissingDescription,
+        Self::WildcardDependency,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::MissingLicense => "missing-license",
+            Self::MissingRustVersion => "missing-rust-version",
+            Self::MissingEdition => "missing-edition",
+            Self::MissingDescription => "missing-description",
+            Self::WildcardDependency => "wildcard-dependency",
+        }
+    }
+
+    /// Whether the rule runs without being enabled in the request.
+    fn enabled_by_default(self) -> bool {
+        !matches!(self, Self::MissingDescription)
+    }
+
+    fn default_severity(self) -> Severity {
+        match self {
+            Self::MissingLicense | Self::WildcardDependency => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|rule| rule.name() == s)
+            .ok_or_else(|| format!("unknown rule: {s}"))
+    }
+}
+
+/// Comma separated rule names, like `missing-license,wildcard-dependency`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rules(Vec<Rule>);
+
+impl TryFrom<String> for Rules {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Rules {
+    fn contains(&self, rule: Rule) -> bool {
+        self.0.contains(&rule)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Which rules run, on top of the defaults. `deny` turns a rule's findings
+/// into errors, enabling it if needed.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enable: Rules,
+    #[serde(default)]
+    pub disable: Rules,
+    #[serde(default)]
+    pub deny: Rules,
+}
+
+impl Config {
+    fn severity(&self, rule: Rule) -> Option<Severity> {
+        if self.deny.contains(rule) {
+            return Some(Severity::Error);
+        }
+        let enabled = !self.disable.contains(rule)
+            && (rule.enabled_by_default() || self.enable.contains(rule));
+        enabled.then(|| rule.default_severity())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub rule: Rule,
+    pub severity: Severity,
+    /// Dotted key path the finding is about, like `dependencies.serde`.
+    pub path: String,
+    pub message: String,
+}
+
+pub fn check(manifest: &Manifest, config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut report = |rule: Rule, path: String, message: String| {
+        if let Some(severity) = config.severity(rule) {
+            findings.push(Finding {
+                rule,
+                severity,
+                path,
+                message,
+            });
+        }
+    };
+
+    if let Some(package) = &manifest.package {
+        if package.license.is_none() && package.license_file.is_none() {
+            report(
+                Rule::MissingLicense,
+                "package.license".to_string(),
+                "no `license` or `license-file` set".to_string(),
+            );
+        }
+        if package.rust_version.is_none() {
+            report(
+                Rule::MissingRustVersion,
+                "package.rust-version".to_string(),
+                "no minimum supported Rust version set".to_string(),
+            );
+        }
+        if package.edition.is_none() {
+            report(
+                Rule::MissingEdition,
+                "package.edition".to_string(),
+                "edition defaults to 2015".to_string(),
+            );
+        }
+        if package.description.is_none() {
+            report(
+                Rule::MissingDescription,
+                "package.description".to_string(),
+                "no `description` set".to_string(),
+            );
+        }
+    }
+
+    let mut tables: Vec<(String, &DepsSet)> = Vec::new();
+    for (table, deps) in [
+        ("dependencies", &manifest.dependencies),
+        ("dev-dependencies", &manifest.dev_dependencies),
+        ("build-dependencies", &manifest.build_dependencies),
+    ] {
+        if let Some(deps) = deps {
+            tables.push((table.to_string(), deps));
+        }
+    }
+    for (target, deps) in manifest.target.iter().flatten() {
+        tables.push((format!("target.{target}.dependencies"), &deps.dependencies));
+        tables.push((
+            format!("target.{target}.dev-dependencies"),
+            &deps.dev_dependencies,
+        ));
+        tables.push((
+            format!("target.{target}.build-dependencies"),
+            &deps.build_dependencies,
+        ));
+    }
+    if let Some(deps) = manifest
+        .workspace
+        .as_ref()
+        .and_then(|w| w.dependencies.as_ref())
+    {
+        tables.push(("workspace.dependencies".to_string(), deps));
+    }
+    for (table, deps) in tables {
+        for (name, dep) in deps {
+            if is_wildcard(dep) {
+                report(
+                    Rule::WildcardDependency,
+                    format!("{table}.{name}"),
+                    format!("`{name}` accepts any version"),
+                );
+            }
+        }
+    }
+
+    findings
+}
+
+/// Registry dependencies without a version requirement, or with `*`.
+fn is_wildcard(dep: &Dependency) -> bool {
+    match dep {
+        Dependency::Simple(req) => req.trim() == "*",
+        Dependency::Detailed(detail) => {
+            detail.path.is_none()
+                && detail.git.is_none()
+                && detail.version.as_deref().is_none_or(|v| v.trim() == "*")
+        }
+        Dependency::Inherited(_) => false,
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt::Write;
+
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use toml::Value;
+
+use crate::extract::{negotiate, Representation};
+
+/// One entry of `package.metadata.orders`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Order {
+    pub item: String,
+    pub quantity: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Price of a single unit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Everything wrong with the order at `index` in the list.
+#[derive(Debug, Serialize)]
+pub struct Invalid {
+    pub index: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Order {
+    pub fn parse(value: &Value) -> Result<Self, Vec<Problem>> {
+        let Some(order) = value.as_table() else {
+            return Err(vec![problem("order", "must be a table")]);
+        };
+        let mut problems = Vec::new();
+
+        let item = match order.get("item") {
+            Some(Value::String(item)) if !item.trim().is_empty() => Some(item.clone()),
+            Some(Value::String(_)) => {
+                problems.push(problem("item", "must not be empty"));
+                None
+            }
+            Some(_) => {
+                problems.push(problem("item", "must be a string"));
+                None
+            }
+            None => {
+                problems.push(problem("item", "is missing"));
+                None
+            }
+        };
+        let quantity = match order.get("quantity") {
+            Some(Value::Integer(quantity)) => match u32::try_from(*quantity) {
+                Ok(quantity) => Some(quantity),
+                Err(_) => {
+                    problems.push(problem("quantity", "must be from 0 to 4294967295"));
+                    None
+                }
+            },
+            Some(_) => {
+                problems.push(problem("quantity", "must be a whole number"));
+                None
+            }
+            None => {
+                problems.push(problem("quantity", "is missing"));
+                None
+            }
+        };
+        let unit = match order.get("unit") {
+            Some(Value::String(unit)) => Some(unit.clone()),
+            Some(_) => {
+                problems.push(problem("unit", "must be a string"));
+                None
+            }
+            None => None,
+        };
+        let price = match order.get("price") {
+            Some(Value::Integer(price)) if *price >= 0 => Some(*price as f64),
+            Some(Value::Float(price)) if price.is_finite() && *price >= 0.0 => Some(*price),
+            Some(Value::Integer(_) | Value::Float(_)) => {
+                problems.push(problem("price", "must not be negative"));
+                None
+            }
+            Some(_) => {
+                problems.push(problem("price", "must be a number"));
+                None
+            }
+            None => None,
+        };
+
+        match (item, quantity) {
+            (Some(item), Some(quantity)) if problems.is_empty() => Ok(Self {
+                item,
+                quantity,
+                unit,
+                price,
+            }),
+            _ => Err(problems),
+        }
+    }
+}
+
+fn problem(field: &'static str, message: &str) -> Problem {
+    Problem {
+        field,
+        message: message.to_string(),
+    }
+}
+
+/// Splits the orders into those that can be used and reports on the rest.
+pub fn parse_all(orders: &[Value]) -> (Vec<Order>, Vec<Invalid>) {
+    let mut valid = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, order) in orders.iter().enumerate() {
+        match Order::parse(order) {
+            Ok(order) => valid.push(order),
+            Err(problems) => invalid.push(Invalid { index, problems }),
+        }
+    }
+    (valid, invalid)
+}
+
+/// Orders of one item in one unit, added up.
+#[derive(Debug, Serialize)]
+pub struct Total {
+    pub item: String,
+    pub unit: Option<String>,
+    pub quantity: u64,
+    /// Price of all the orders, if every one of them has a price.
+    pub total: Option<f64>,
+}
+
+/// Groups orders by item and unit, in the order the items first appear.
+pub fn aggregate(orders: &[Order]) -> Vec<Total> {
+    let mut totals: Vec<Total> = Vec::new();
+    for order in orders {
+        let price = order.price.map(|price| price * order.quantity as f64);
+        let existing = totals
+            .iter_mut()
+            .find(|total| total.item == order.item && total.unit == order.unit);
+        match existing {
+            Some(total) => {
+                total.quantity += order.quantity as u64;
+                total.total = total.total.zip(price).map(|(total, price)| total + price);
+            }
+            None => totals.push(Total {
+                item: order.item.clone(),
+                unit: order.unit.clone(),
+                quantity: order.quantity as u64,
+                total: price,
+            }),
+        }
+    }
+    totals
+}
+
+/// Representation of the totals picked from the `Accept` header. Plain text
+/// unless JSON or CSV is asked for.
+#[derive(Clone, Copy)]
+pub enum Report {
+    Text,
+    Json,
+    Csv,
+}
+
+impl Report {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        use Representation::{Csv, Json, Text};
+        match negotiate(headers, &[Text, Json, Csv], Text) {
+            Some(Json) => Self::Json,
+            Some(Csv) => Self::Csv,
+            _ => Self::Text,
+        }
+    }
+
+    /// The grand total is only given when every group has a total.
+    pub fn render(self, totals: &[Total]) -> Response {
+        let grand_total = totals
+            .iter()
+            .try_fold(0.0, |sum, total| total.total.map(|total| sum + total));
+        match self {
+            Self::Text => {
+                let mut output = String::new();
+                for total in totals {
+                    write!(output, "{}: {}", total.item, total.quantity).unwrap();
+                    if let Some(unit) = &total.unit {
+                        write!(output, " {unit}").unwrap();
+                    }
+                    if let Some(price) = total.total {
+                        write!(output, " = {price:.2}").unwrap();
+                    }
+                    output.push('\n');
+                }
+                if let Some(grand_total) = grand_total {
+                    writeln!(output, "Total: {grand_total:.2}").unwrap();
+                }
+                output.into_response()
+            }
+            Self::Json => Json(json!({"items": totals, "total": grand_total})).into_response(),
+            Self::Csv => {
+                let mut output = String::from("item,quantity,unit,total\n");
+                for total in totals {
+                    let price = total.total.map(|price| format!("{price:.2}"));
+                    writeln!(
+                        output,
+                        "{},{},{},{}",
+                        csv_field(&total.item),
+                        total.quantity,
+                        csv_field(total.unit.as_deref().unwrap_or_default()),
+                        price.unwrap_or_default(),
+                    )
+                    .unwrap();
+                }
+                ([(CONTENT_TYPE, "text/csv")], output).into_response()
+            }
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
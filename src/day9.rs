@@ -1,37 +1,36 @@
-use std::{ops::DerefMut, sync::Arc, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
 use axum::{
     extract::{Request, State},
-    http::{header::CONTENT_TYPE, StatusCode},
-    response::IntoResponse,
+    http::StatusCode,
+    response::{IntoResponse, Response},
     routing::post,
     Json, RequestExt, Router,
 };
-use leaky_bucket::RateLimiter;
 use serde::Deserialize;
 use serde_json::json;
-use tokio::sync::Mutex;
 
-const BUCKET_SIZE: u8 = 5;
-const BUCKET_REFILL_SECS: u64 = 1;
+use crate::{
+    extract::{Expected, Media, Query, ValidPayload},
+    rate_limit::{Client, Limiter},
+};
 
-type MilkBucket = Arc<Mutex<RateLimiter>>;
+const BUCKET_SIZE: u32 = 5;
+const BUCKET_REFILL_SECS: u64 = 1;
 
-fn filled_bucket() -> RateLimiter {
-    RateLimiter::builder()
-        .initial(BUCKET_SIZE as usize)
-        .max(BUCKET_SIZE as usize)
-        .interval(Duration::from_secs(BUCKET_REFILL_SECS))
-        .build()
-}
+/// A bucket of milk per client.
+type MilkBuckets = Arc<Limiter<Client>>;
 
 pub fn router() -> Router {
-    let limiter = Arc::new(Mutex::new(filled_bucket()));
+    let buckets = Arc::new(Limiter::new(
+        BUCKET_SIZE,
+        Duration::from_secs(BUCKET_REFILL_SECS),
+    ));
 
     Router::new()
         .route("/9/milk", post(task1))
         .route("/9/refill", post(task4))
-        .with_state(limiter.clone())
+        .with_state(buckets)
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,48 +43,91 @@ enum Unit {
 }
 
 #[axum::debug_handler]
-async fn task1(State(bucket): State<MilkBucket>, req: Request) -> impl IntoResponse {
-    let got_milk = bucket.lock().await.try_acquire(1);
-    if !got_milk {
-        return (StatusCode::TOO_MANY_REQUESTS, "No milk available\n").into_response();
+async fn task1(
+    State(buckets): State<MilkBuckets>,
+    client: Client,
+    req: Request,
+) -> impl IntoResponse {
+    let decision = buckets.acquire(client);
+    if !decision.allowed {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            decision,
+            "No milk available\n",
+        )
+            .into_response();
     }
+    (decision, withdraw(req).await).into_response()
+}
 
-    let content_type_header = req.headers().get(CONTENT_TYPE);
-    let content_type = content_type_header.and_then(|v| v.to_str().ok());
-    if let Some(content_type) = content_type {
-        if content_type.starts_with("application/json") {
-            let Json(payload) = match req.extract::<Json<Unit>, _>().await {
-                Ok(v) => v,
-                Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
-            };
+async fn withdraw(req: Request) -> Response {
+    // only a body that names a unit as a single-key table asks for a
+    // conversion; form data and plain YAML maps can't be read as `Unit`, so
+    // like any other body they're a plain withdrawal
+    let media = Media::from_headers(req.headers());
+    if matches!(media, Some(Media::Json | Media::Toml)) {
+        let ValidPayload(payload) = match req.extract::<ValidPayload<Unit>, _>().await {
+            Ok(v) => v,
+            Err(e) => return e.into_response(),
+        };
 
-            match payload {
-                Unit::Liters(v) => {
-                    let gallons = v * 0.264172;
-                    return Json(json!({"gallons": gallons})).into_response();
-                }
-                Unit::Litres(v) => {
-                    let pints = v * 1.759754;
-                    return Json(json!({"pints": pints})).into_response();
-                }
-                Unit::Gallons(v) => {
-                    let liters = v * 3.785412;
-                    return Json(json!({"liters": liters})).into_response();
-                }
-                Unit::Pints(v) => {
-                    let litres = v * 0.5682612;
-                    return Json(json!({"litres": litres})).into_response();
-                }
+        match payload {
+            Unit::Liters(v) => {
+                let gallons = v * 0.264172;
+                return Json(json!({"gallons": gallons})).into_response();
+            }
+            Unit::Litres(v) => {
+                let pints = v * 1.759754;
+                return Json(json!({"pints": pints})).into_response();
+            }
+            Unit::Gallons(v) => {
+                let liters = v * 3.785412;
+                return Json(json!({"liters": liters})).into_response();
+            }
+            Unit::Pints(v) => {
+                let litres = v * 0.5682612;
+                return Json(json!({"litres": litres})).into_response();
             }
         }
     }
     (StatusCode::OK, "Milk withdrawn\n").into_response()
 }
 
+#[derive(Deserialize)]
+struct RefillParams {
+    /// Refill the bucket of the client at this address.
+    ip: Option<IpAddr>,
+    /// Refill the bucket of the client with this API key.
+    api_key: Option<String>,
+}
+
+impl Expected for RefillParams {
+    fn expected(field: &str) -> Option<&'static str> {
+        match field {
+            "ip" => Some("IPv4 or IPv6 address"),
+            _ => None,
+        }
+    }
+}
+
+/// Refill every bucket, or just the ones for `ip` and `api_key`.
 #[axum::debug_handler]
-async fn task4(State(bucket): State<MilkBucket>) -> impl IntoResponse {
-    let mut lock = bucket.lock().await;
-    let bucket = lock.deref_mut();
-    *bucket = filled_bucket();
+async fn task4(
+    State(buckets): State<MilkBuckets>,
+    Query(params): Query<RefillParams>,
+) -> impl IntoResponse {
+    // buckets start out full, so forgetting one refills it
+    let clients: Vec<Client> = params
+        .ip
+        .map(Client::Ip)
+        .into_iter()
+        .chain(params.api_key.map(Client::ApiKey))
+        .collect();
+    if clients.is_empty() {
+        buckets.refill(None);
+    }
+    for client in &clients {
+        buckets.refill(Some(client));
+    }
     StatusCode::OK
 }
@@ -0,0 +1,439 @@
+use std::{convert::Infallible, fmt::Display, str::Utf8Error};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, FromRequestParts, Request},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Response representation picked from the `Accept` header. Plain text
+/// unless JSON is preferred.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Format
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        use Representation::{Json, Text};
+        Ok(match negotiate(&parts.headers, &[Text, Json], Text) {
+            Some(Json) => Self::Json,
+            _ => Self::Text,
+        })
+    }
+}
+
+/// Formats a response can be negotiated into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Text,
+    Json,
+    Csv,
+    Toml,
+    Yaml,
+}
+
+impl Representation {
+    /// The media types naming this format, the preferred one first.
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Text => &["text/plain"],
+            Self::Json => &["application/json"],
+            Self::Csv => &["text/csv"],
+            Self::Toml => &["application/toml"],
+            Self::Yaml => &[
+                "application/yaml",
+                "application/x-yaml",
+                "text/yaml",
+                "text/x-yaml",
+            ],
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        self.media_types()[0]
+    }
+}
+
+/// The one of `offered` the `Accept` header prefers: by quality, then in
+/// the header's order. `default` when there's no header, or for `*/*`.
+/// `None` when nothing offered is acceptable.
+pub fn negotiate(
+    headers: &HeaderMap,
+    offered: &[Representation],
+    default: Representation,
+) -> Option<Representation> {
+    let mut ranges: Vec<(String, f32)> = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next()?.trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, q)| q.trim().parse().ok())?;
+            (!media.is_empty()).then_some((media, quality))
+        })
+        .collect();
+    if ranges.is_empty() {
+        return Some(default);
+    }
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    // the default goes first among those a wildcard matches
+    let candidates = || std::iter::once(default).chain(offered.iter().copied());
+    ranges
+        .iter()
+        .filter(|(_, quality)| *quality > 0.0)
+        .find_map(|(range, _)| match range.strip_suffix("/*") {
+            Some("*") => Some(default),
+            Some(kind) => candidates().find(|offer| {
+                offer
+                    .media_types()
+                    .iter()
+                    .any(|media| media.split('/').next() == Some(kind))
+            }),
+            None => candidates().find(|offer| offer.media_types().contains(&range.as_str())),
+        })
+}
+
+/// Describes the accepted format of each query parameter, for error responses.
+pub trait Expected {
+    fn expected(_field: &str) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Like axum's `Query`, but rejects with a JSON body naming the parameter
+/// that failed and what it should have looked like.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned + Expected,
+    S: Send + Sync,
+{
+    type Rejection = QueryRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let pairs = form_urlencoded::parse(query.as_bytes());
+        serde_path_to_error::deserialize(serde_urlencoded::Deserializer::new(pairs))
+            .map(Self)
+            .map_err(|e| {
+                let message = e.inner().to_string();
+                let field = match e.path().to_string() {
+                    path if path != "." => Some(path),
+                    // missing fields fail on the struct, not on the field
+                    _ => message
+                        .strip_prefix("missing field `")
+                        .and_then(|rest| rest.strip_suffix('`'))
+                        .map(str::to_string),
+                };
+                let value = field.as_ref().and_then(|field| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == field)
+                        .map(|(_, value)| value.into_owned())
+                });
+                let expected = field.as_deref().and_then(T::expected);
+                QueryRejection {
+                    error: "Invalid query parameter",
+                    field,
+                    value,
+                    expected,
+                    message,
+                }
+            })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryRejection {
+    error: &'static str,
+    field: Option<String>,
+    value: Option<String>,
+    expected: Option<&'static str>,
+    message: String,
+}
+
+impl IntoResponse for QueryRejection {
+    fn into_response(self) -> Response {
+        (StatusCode::BAD_REQUEST, Json(self)).into_response()
+    }
+}
+
+/// Body formats `ValidPayload` can decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Media {
+    Toml,
+    Yaml,
+    Json,
+    Form,
+}
+
+impl Media {
+    /// Reads a `Content-Type` like `application/vnd.api+json; charset=utf-8`.
+    /// Only UTF-8 bodies are supported.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut params = content_type.split(';');
+        let essence = params.next()?.trim().to_ascii_lowercase();
+        let utf8 = params.all(|param| match param.split_once('=') {
+            Some((name, charset)) if name.trim().eq_ignore_ascii_case("charset") => {
+                let charset = charset.trim().trim_matches('"');
+                charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("us-ascii")
+            }
+            _ => true,
+        });
+        if !utf8 {
+            return None;
+        }
+        let (kind, subtype) = essence.split_once('/')?;
+        let suffix = subtype.rsplit_once('+').map(|(_, suffix)| suffix);
+        match (kind, subtype, suffix) {
+            ("application", "json", _) | (_, _, Some("json")) => Some(Self::Json),
+            ("application", "toml", _) | (_, _, Some("toml")) => Some(Self::Toml),
+            ("application" | "text", "yaml" | "x-yaml", _) | (_, _, Some("yaml")) => {
+                Some(Self::Yaml)
+            }
+            ("application", "x-www-form-urlencoded", _) => Some(Self::Form),
+            _ => None,
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        Self::from_content_type(content_type)
+    }
+
+    /// Failures say where the body went wrong, as far as the parser knows.
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, PayloadRejection> {
+        match self {
+            Self::Toml => {
+                let text = std::str::from_utf8(body)?;
+                serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(|e| {
+                    let location = e.inner().span().map(|span| line_column(text, span.start));
+                    self.invalid(body, Some(e.path()), e.inner().message(), location)
+                })
+            }
+            Self::Yaml => {
+                serde_path_to_error::deserialize(serde_yaml::Deserializer::from_slice(body))
+                    .map_err(|e| {
+                        let location = e.inner().location().map(|l| (l.line(), l.column()));
+                        self.invalid(body, Some(e.path()), e.inner(), location)
+                    })
+            }
+            Self::Json => {
+                let mut deserializer = serde_json::Deserializer::from_slice(body);
+                let json_location = |e: &serde_json::Error| Some((e.line(), e.column()));
+                let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                    let location = json_location(e.inner());
+                    self.invalid(body, Some(e.path()), e.inner(), location)
+                })?;
+                // nothing but whitespace may follow
+                deserializer.end().map_err(|e| {
+                    let location = json_location(&e);
+                    self.invalid(body, None, e, location)
+                })?;
+                Ok(value)
+            }
+            Self::Form => {
+                let pairs = form_urlencoded::parse(body);
+                serde_path_to_error::deserialize(serde_urlencoded::Deserializer::new(pairs))
+                    .map_err(|e| self.invalid(body, Some(e.path()), e.inner(), None))
+            }
+        }
+    }
+
+    fn invalid(
+        self,
+        body: &[u8],
+        path: Option<&serde_path_to_error::Path>,
+        message: impl Display,
+        location: Option<(usize, usize)>,
+    ) -> PayloadRejection {
+        // parsers report a line of 0 when they can't tell
+        let location = location.filter(|&(line, _)| line > 0);
+        let path = path.map(ToString::to_string).filter(|path| path != ".");
+        PayloadRejection::Invalid {
+            media: self,
+            message: message.to_string(),
+            diagnostic: Diagnostic {
+                line: location.map(|(line, _)| line),
+                column: location.map(|(_, column)| column),
+                snippet: location.and_then(|(line, _)| {
+                    let text = String::from_utf8_lossy(body);
+                    text.lines().nth(line - 1).map(str::to_string)
+                }),
+                path,
+            },
+        }
+    }
+}
+
+/// One-based line and column of a byte offset.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+impl Display for Media {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Toml => "TOML",
+            Self::Yaml => "YAML",
+            Self::Json => "JSON",
+            Self::Form => "form data",
+        })
+    }
+}
+
+/// Request body decoded according to its `Content-Type`.
+pub struct ValidPayload<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ValidPayload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = PayloadRejection;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(media) = Media::from_headers(req.headers()) else {
+            eprintln!("Unsupported media {:?}", req.headers().get(CONTENT_TYPE));
+            return Err(PayloadRejection::UnsupportedMedia);
+        };
+        let payload = Bytes::from_request(req, state).await?;
+        media.decode(&payload).map(Self)
+    }
+}
+
+/// Where a body failed to decode. Lines and columns count from one.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// The line the error is on.
+    pub snippet: Option<String>,
+    /// Key path to the value that failed, like `package.keywords[0]`.
+    pub path: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayloadRejection {
+    #[error("Media type not supported")]
+    UnsupportedMedia,
+    #[error(transparent)]
+    Bytes(#[from] BytesRejection),
+    #[error("Request body isn't valid UTF-8")]
+    Utf8(#[from] Utf8Error),
+    #[error("Failed to deserialize the request body as {media}")]
+    Invalid {
+        media: Media,
+        message: String,
+        diagnostic: Diagnostic,
+    },
+}
+
+impl PayloadRejection {
+    /// Like `into_response`, but summarising a body that failed to decode
+    /// as `error` rather than by its media type.
+    pub fn respond_as(self, error: &str) -> Response {
+        #[derive(Serialize)]
+        struct Body<'a> {
+            error: &'a str,
+            message: &'a str,
+            #[serde(flatten)]
+            diagnostic: &'a Diagnostic,
+        }
+
+        match &self {
+            Self::Invalid {
+                message,
+                diagnostic,
+                ..
+            } => (
+                StatusCode::BAD_REQUEST,
+                Json(Body {
+                    error,
+                    message,
+                    diagnostic,
+                }),
+            )
+                .into_response(),
+            _ => self.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for PayloadRejection {
+    fn into_response(self) -> Response {
+        match self {
+            Self::UnsupportedMedia => StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response(),
+            Self::Bytes(rejection) => rejection.into_response(),
+            Self::Utf8(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+            Self::Invalid { .. } => {
+                let error = self.to_string();
+                self.respond_as(&error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Representation::{Csv, Json, Text, Toml, Yaml};
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiate_defaults() {
+        let offered = [Toml, Yaml, Json];
+        assert_eq!(negotiate(&HeaderMap::new(), &offered, Yaml), Some(Yaml));
+        assert_eq!(negotiate(&accept("*/*"), &offered, Json), Some(Json));
+        assert_eq!(
+            negotiate(&accept("application/*"), &offered, Yaml),
+            Some(Yaml)
+        );
+        assert_eq!(negotiate(&accept("text/*"), &[Text, Csv], Csv), Some(Csv));
+        assert_eq!(negotiate(&accept("text/html"), &offered, Toml), None);
+    }
+
+    #[test]
+    fn negotiate_by_quality_then_order() {
+        let offered = [Text, Json, Csv];
+        let headers = accept("text/plain;q=0.5, text/csv, application/json");
+        assert_eq!(negotiate(&headers, &offered, Text), Some(Csv));
+        let headers = accept("application/json;q=0, */*;q=0.1");
+        assert_eq!(negotiate(&headers, &offered, Text), Some(Text));
+        assert_eq!(negotiate(&accept("text/x-yaml"), &[Yaml], Yaml), Some(Yaml));
+    }
+}
@@ -1,11 +1,17 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
 use axum::{
-    http::{header, StatusCode},
+    extract::Request,
+    http::{header, Method, StatusCode},
     response::IntoResponse,
     routing::get,
     Router,
 };
+use rate_limit::{client_ip, RateLimitLayer};
+use shuttle_runtime::CustomError;
 use tower_http::services::ServeDir;
 
+mod advisory;
 mod day12;
 mod day16;
 mod day19;
@@ -13,23 +19,55 @@ mod day2;
 mod day23;
 mod day5;
 mod day9;
+mod extract;
+mod rate_limit;
 
 #[shuttle_runtime::main]
-async fn main(#[shuttle_shared_db::Postgres] pool: sqlx::PgPool) -> shuttle_axum::ShuttleAxum {
+async fn main(
+    #[shuttle_shared_db::Postgres] pool: sqlx::PgPool,
+) -> Result<Service, shuttle_runtime::Error> {
     sqlx::migrate!().run(&pool).await.unwrap();
+    let advisory_db = std::env::var("ADVISORY_DB").unwrap_or_else(|_| "advisory-db".to_string());
+    let advisories = Arc::new(advisory::Database::load(advisory_db));
+
+    // reads stay unlimited, writes get 10 a second per client address
+    let quote_writes = RateLimitLayer::new(10, Duration::from_secs(1), |req: &Request| {
+        client_ip(req).filter(|_| req.method() != Method::GET)
+    });
+    let wraps = RateLimitLayer::new(5, Duration::from_secs(1), |req: &Request| {
+        client_ip(req).filter(|_| req.uri().path() == "/16/wrap")
+    });
 
     let router = Router::new()
         .route("/", get(hello_world))
         .route("/-1/seek", get(found))
         .merge(day2::router())
-        .merge(day5::router())
+        .merge(day5::router(advisories.clone()))
         .merge(day9::router())
-        .merge(day12::router())
-        .merge(day16::router())
-        .merge(day19::router(pool))
-        .merge(day23::router())
+        .merge(day12::router(pool.clone(), Duration::from_secs(30 * 60)).await)
+        .merge(day16::router().layer(wraps))
+        .merge(day19::router(pool).layer(quote_writes))
+        .merge(day23::router(advisories))
         .nest_service("/assets", ServeDir::new("assets"));
-    Ok(router.into())
+    Ok(Service(router))
+}
+
+/// Like shuttle's axum service, but with each connection's peer address
+/// available as `ConnectInfo`, so clients can be told apart.
+struct Service(Router);
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for Service {
+    async fn bind(self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = shuttle_runtime::tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(CustomError::new)?;
+        let service = self.0.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, service)
+            .await
+            .map_err(CustomError::new)?;
+        Ok(())
+    }
 }
 
 async fn hello_world() -> &'static str {
@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{
+        header::RETRY_AFTER, request::Parts, Extensions, HeaderMap, HeaderName, HeaderValue,
+        StatusCode,
+    },
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+};
+use tower::{Layer, Service};
+
+/// Keys tracked at once.
+const MAX_KEYS: usize = 1024;
+
+/// A token bucket per key: `capacity` requests at once, and one more every
+/// `interval`. A full bucket is the same as no bucket, so only those are
+/// forgotten to make room for new keys; while every tracked key is still
+/// refilling, new keys are turned away instead.
+pub struct Limiter<K> {
+    capacity: u32,
+    interval: Duration,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: u32,
+    /// When the last token was added, or the bucket was last seen full.
+    refilled_at: Instant,
+}
+
+/// Outcome of taking a token, and the `RateLimit-*` headers describing it.
+pub struct Decision {
+    pub allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// Until the bucket is full again.
+    reset: Duration,
+    /// Until the next token.
+    next: Duration,
+}
+
+impl<K: Hash + Eq + Clone> Limiter<K> {
+    pub fn new(capacity: u32, interval: Duration) -> Self {
+        Self {
+            capacity,
+            interval: interval.max(Duration::from_nanos(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn acquire(&self, key: K) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) && buckets.len() >= MAX_KEYS {
+            buckets.retain(|_, bucket| self.until_full(bucket, now) > Duration::ZERO);
+            if buckets.len() >= MAX_KEYS {
+                let next = buckets
+                    .values()
+                    .map(|bucket| self.until_full(bucket, now))
+                    .min()
+                    .unwrap_or(self.interval);
+                return Decision {
+                    allowed: false,
+                    limit: self.capacity,
+                    remaining: 0,
+                    reset: next,
+                    next,
+                };
+            }
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            refilled_at: now,
+        });
+
+        let refills = (now - bucket.refilled_at).as_nanos() / self.interval.as_nanos();
+        let refills = u32::try_from(refills).unwrap_or(u32::MAX);
+        bucket.tokens = bucket.tokens.saturating_add(refills).min(self.capacity);
+        if bucket.tokens == self.capacity {
+            bucket.refilled_at = now;
+        } else {
+            bucket.refilled_at += self.interval * refills;
+        }
+
+        let allowed = bucket.tokens > 0;
+        if allowed {
+            bucket.tokens -= 1;
+        }
+        Decision {
+            allowed,
+            limit: self.capacity,
+            remaining: bucket.tokens,
+            reset: self.until_full(bucket, now),
+            next: self.interval.saturating_sub(now - bucket.refilled_at),
+        }
+    }
+
+    /// Zero once the bucket has refilled completely.
+    fn until_full(&self, bucket: &Bucket, now: Instant) -> Duration {
+        let missing = self.capacity.saturating_sub(bucket.tokens);
+        (self.interval * missing).saturating_sub(now - bucket.refilled_at)
+    }
+
+    /// Refill the bucket for `key`, or every bucket.
+    pub fn refill(&self, key: Option<&K>) {
+        let mut buckets = self.buckets.lock().unwrap();
+        match key {
+            Some(key) => {
+                buckets.remove(key);
+            }
+            None => buckets.clear(),
+        }
+    }
+}
+
+impl Decision {
+    fn apply(&self, headers: &mut HeaderMap) {
+        // whole seconds, rounded up so clients don't retry too early
+        let seconds = |d: Duration| d.as_secs() + u64::from(d.subsec_nanos() > 0);
+        let mut insert = |name: &'static str, value: u64| {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        };
+        insert("ratelimit-limit", self.limit.into());
+        insert("ratelimit-remaining", self.remaining.into());
+        insert("ratelimit-reset", seconds(self.reset));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds(self.next)));
+        }
+    }
+}
+
+impl IntoResponseParts for Decision {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        self.apply(res.headers_mut());
+        Ok(res)
+    }
+}
+
+/// Proxies in front of the server that append to `X-Forwarded-For`, from
+/// `TRUSTED_PROXIES`. Shuttle's own proxy is one.
+static TRUSTED_PROXIES: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("TRUSTED_PROXIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1)
+});
+
+/// Who's asking: the `X-Api-Key` header if given, otherwise the client's IP
+/// address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+impl Client {
+    fn from_headers(headers: &HeaderMap, extensions: &Extensions) -> Option<Self> {
+        if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+            return Some(Self::ApiKey(key.to_string()));
+        }
+        address(headers, extensions).map(Self::Ip)
+    }
+}
+
+/// The client's IP address, ignoring any API key, since clients can make up
+/// as many of those as they like. `None` when the server isn't told the peer
+/// address and there's no forwarded one either.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    address(req.headers(), req.extensions())
+}
+
+fn address(headers: &HeaderMap, extensions: &Extensions) -> Option<IpAddr> {
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    forwarded_for(headers, *TRUSTED_PROXIES).or(peer)
+}
+
+/// The address the outermost of `proxies` trusted proxies saw the request
+/// come from. Entries left of it are whatever the client sent, so can't be
+/// trusted.
+fn forwarded_for(headers: &HeaderMap, proxies: usize) -> Option<IpAddr> {
+    if proxies == 0 {
+        return None;
+    }
+    let entries: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    let index = entries.len().checked_sub(proxies)?;
+    entries[index].parse().ok()
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Client
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_headers(&parts.headers, &parts.extensions).ok_or_else(|| {
+            eprintln!("No peer address, serve with `into_make_service_with_connect_info`");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can't tell clients apart\n",
+            )
+        })
+    }
+}
+
+/// Rate limits the requests `key` maps to a key, each key on its own.
+/// Requests it maps to `None` pass through untouched.
+pub struct RateLimitLayer<F, K> {
+    limiter: Arc<Limiter<K>>,
+    key: F,
+}
+
+impl<F, K> RateLimitLayer<F, K>
+where
+    F: Fn(&Request) -> Option<K>,
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: u32, interval: Duration, key: F) -> Self {
+        Self {
+            limiter: Arc::new(Limiter::new(capacity, interval)),
+            key,
+        }
+    }
+}
+
+impl<F: Clone, K> Clone for RateLimitLayer<F, K> {
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<S, F: Clone, K> Layer<S> for RateLimitLayer<F, K> {
+    type Service = RateLimit<S, F, K>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+pub struct RateLimit<S, F, K> {
+    inner: S,
+    limiter: Arc<Limiter<K>>,
+    key: F,
+}
+
+impl<S: Clone, F: Clone, K> Clone for RateLimit<S, F, K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<S, F, K> Service<Request> for RateLimit<S, F, K>
+where
+    S: Service<Request, Response = Response>,
+    S::Error: Send + 'static,
+    S::Future: Send + 'static,
+    F: Fn(&Request) -> Option<K>,
+    K: Hash + Eq + Clone,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let Some(key) = (self.key)(&req) else {
+            return Box::pin(self.inner.call(req));
+        };
+        let decision = self.limiter.acquire(key);
+        if !decision.allowed {
+            let response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                decision,
+                "Too many requests\n",
+            );
+            return Box::pin(std::future::ready(Ok(response.into_response())));
+        }
+        let response = self.inner.call(req);
+        Box::pin(async move { Ok((decision, response.await?).into_response()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_keys_dont_evict_draining_buckets() {
+        let limiter = Limiter::new(1, Duration::from_secs(60));
+        assert!(limiter.acquire(0).allowed);
+        for key in 1..MAX_KEYS {
+            assert!(limiter.acquire(key).allowed);
+        }
+        assert!(!limiter.acquire(MAX_KEYS).allowed);
+        assert!(!limiter.acquire(0).allowed);
+    }
+
+    #[test]
+    fn full_buckets_make_room() {
+        let limiter = Limiter::new(1, Duration::from_nanos(1));
+        for key in 0..MAX_KEYS {
+            assert!(limiter.acquire(key).allowed);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(limiter.acquire(MAX_KEYS).allowed);
+    }
+}